@@ -0,0 +1,2173 @@
+use crate::{
+    classify_clear, spawn_position, Controls, GameMode, GameStatus, Piece, PieceSet, PieceType,
+    Player, Position, Puzzle, Randomizer, RotationSystem, State, TSpin, Trail, Vec2D,
+    CHEESE_INTERVAL, GARBAGE_SENT, GRAVITY_FLOOR_DEFAULT, PREVIEW_COUNT, STAGE_COLS, STAGE_ROWS,
+};
+use log::info;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const SOFT_DROP_POINTS: usize = 1; // per row
+const HARD_DROP_POINTS: usize = 2; // per row
+pub(crate) const LOCK_RESET_CAP: usize = 15; // default
+const LAST_KICK: usize = 4; // the kick that lifts a T deep into a slot
+const UNDO_NODES: usize = 100; // practice positions kept for undo and redo
+
+// SRS wall kicks, the (x, y) offsets tried in order for a clockwise turn out
+// of each rotation state (0 -> R, R -> 2, 2 -> L, L -> 0), y growing downwards
+const JLSTZ_KICKS: [[(isize, isize); 5]; 4] = [
+    [(0, 0), (-1, 0), (-1, -1), (0, 2), (-1, 2)],
+    [(0, 0), (1, 0), (1, 1), (0, -2), (1, -2)],
+    [(0, 0), (1, 0), (1, -1), (0, 2), (1, 2)],
+    [(0, 0), (-1, 0), (-1, 1), (0, -2), (-1, -2)],
+];
+const I_KICKS: [[(isize, isize); 5]; 4] = [
+    [(0, 0), (-2, 0), (1, 0), (-2, 1), (1, -2)],
+    [(0, 0), (-1, 0), (2, 0), (-1, -2), (2, 1)],
+    [(0, 0), (2, 0), (-1, 0), (2, -1), (-1, 2)],
+    [(0, 0), (1, 0), (-2, 0), (1, 2), (-2, -1)],
+];
+// sideways fallbacks once the SRS kicks are used up
+// the simple system only tries the rotation in place and a row up
+const SIMPLE_KICKS: [(isize, isize); 2] = [(0, 0), (0, -1)];
+const WALL_NUDGES: [(isize, isize); 4] = [(1, 0), (-1, 0), (2, 0), (-2, 0)];
+// 180 kicks for every piece (0 -> 2, R -> L, 2 -> 0, L -> R), no SRS standard
+// exists so these follow the common SRS+ table
+const FLIP_KICKS: [[(isize, isize); 6]; 4] = [
+    [(0, 0), (0, -1), (1, -1), (-1, -1), (1, 0), (-1, 0)],
+    [(0, 0), (1, 0), (1, -2), (1, -1), (0, -2), (0, -1)],
+    [(0, 0), (0, 1), (-1, 1), (1, 1), (-1, 0), (1, 0)],
+    [(0, 0), (-1, 0), (-1, -2), (-1, -1), (0, -2), (0, -1)],
+];
+
+/// Options a game is started with.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Settings {
+    pub n_rows: usize, // visible rows
+    pub n_cols: usize,
+    pub start_level: usize,
+    pub safety_rows: usize,
+    pub safe_first_piece: bool,
+    pub randomizer: Randomizer,
+    pub mode: GameMode,
+    pub preview_count: usize,
+    #[serde(default = "default_lock_reset_cap")]
+    pub lock_reset_cap: usize, // lock delay restarts a grounded piece gets
+    #[serde(default = "default_gravity_floor")]
+    // the fastest guideline gravity gets, however high the level. The NES
+    // profile ignores it and runs its frame table down to the killscreen.
+    pub gravity_floor_ms: u64,
+    #[serde(default)]
+    pub soft_drop_ms: u64, // per row while soft drop is held, 0 follows gravity
+    #[serde(default)]
+    pub rotation_system: RotationSystem,
+    #[serde(default)]
+    pub hold_keeps_rotation: bool, // guideline hold brings pieces back in spawn orientation
+    #[serde(default)]
+    pub marathon_goal: usize, // lines, 0 for an endless Marathon
+    #[serde(default)]
+    pub script: Vec<PieceType>, // pieces dealt in order instead of the randomizer
+    #[serde(default = "default_loop_script")]
+    pub loop_script: bool, // start the script over, rather than go on with the randomizer
+    #[serde(default)]
+    pub custom_pieces: bool, // dealt by a `State::set_piece_source` source no replay rebuilds
+    #[serde(default)]
+    pub puzzle: Option<Puzzle>, // dealt instead of the script, see `State::with_puzzle`
+    pub seed: Option<u64>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            n_rows: STAGE_ROWS,
+            n_cols: STAGE_COLS,
+            start_level: 1,
+            safety_rows: 0,
+            safe_first_piece: true,
+            randomizer: Randomizer::Bag,
+            mode: GameMode::Marathon,
+            preview_count: PREVIEW_COUNT,
+            lock_reset_cap: LOCK_RESET_CAP,
+            gravity_floor_ms: GRAVITY_FLOOR_DEFAULT,
+            soft_drop_ms: 0,
+            rotation_system: RotationSystem::Srs,
+            hold_keeps_rotation: false,
+            marathon_goal: 0,
+            script: Vec::new(),
+            loop_script: false,
+            custom_pieces: false,
+            puzzle: None,
+            seed: None,
+        }
+    }
+}
+
+impl Settings {
+    // the pieces dealt before the randomizer and whether they start over
+    pub(crate) fn script(&self) -> (&[PieceType], bool) {
+        match &self.puzzle {
+            Some(puzzle) => (&puzzle.pieces, false),
+            None => (&self.script, self.loop_script),
+        }
+    }
+}
+
+fn default_lock_reset_cap() -> usize {
+    LOCK_RESET_CAP
+}
+
+fn default_gravity_floor() -> u64 {
+    GRAVITY_FLOOR_DEFAULT
+}
+
+// scripts always looped in replays recorded before there was a choice
+fn default_loop_script() -> bool {
+    true
+}
+
+/// What a `Game::step` did, so the caller can react to it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StepOutcome {
+    Moved,
+    Blocked,
+    Grounded, // can't fall any further, the lock delay should start
+    LockReset,
+    Locked { rows_cleared: usize },
+    GameOver,
+    Paused,
+    Resumed,
+}
+
+// a position undo and redo go back to
+#[derive(Clone)]
+pub(crate) struct Snapshot {
+    stage: Vec2D,
+    player: Player,
+    game_status: GameStatus,
+    next_queue: Vec<PieceType>,
+    held_piece: Option<PieceType>,
+    held_rotation: usize,
+    hold_used: bool,
+    droughts: HashMap<PieceType, usize>,
+    piece_counts: HashMap<PieceType, usize>,
+}
+
+// the positions practice mode went through, one taken just before each piece
+// locks and one of where the game got to when an undo leaves it. Undoing and
+// trying something else starts a new branch, redo follows either.
+#[derive(Default)]
+pub(crate) struct UndoTree {
+    nodes: Vec<UndoNode>,   // oldest first, so parents come before their children
+    current: Option<usize>, // the position the game last went on from
+    at_current: bool,       // back at that position, after an undo or redo
+    branch: usize,          // which child of the current position redo goes to
+}
+
+struct UndoNode {
+    snapshot: Snapshot,
+    parent: Option<usize>,
+}
+
+impl UndoTree {
+    // the oldest position makes room, its children become roots
+    fn push(&mut self, snapshot: Snapshot) {
+        if self.nodes.len() == UNDO_NODES {
+            self.nodes.remove(0);
+            for node in self.nodes.iter_mut() {
+                node.parent = node.parent.and_then(|parent| parent.checked_sub(1));
+            }
+            self.current = self.current.and_then(|current| current.checked_sub(1));
+        }
+        self.nodes.push(UndoNode {
+            snapshot,
+            parent: self.current,
+        });
+        self.current = Some(self.nodes.len() - 1);
+        self.at_current = false;
+        self.branch = 0;
+    }
+
+    fn children(&self) -> Vec<usize> {
+        match self.current {
+            Some(current) if self.at_current => (current + 1..self.nodes.len())
+                .filter(|&node| self.nodes[node].parent == Some(current))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // where undo goes: the current position, or its parent when the game is
+    // still at it
+    fn undo_target(&self) -> Option<usize> {
+        let current = self.current?;
+        if self.at_current {
+            self.nodes[current].parent
+        } else {
+            Some(current)
+        }
+    }
+
+    fn redo_target(&self) -> Option<usize> {
+        let children = self.children();
+        children.get(self.branch % children.len().max(1)).cloned()
+    }
+
+    /// The branch redo follows and how many there are, if there is a choice.
+    pub(crate) fn branches(&self) -> Option<(usize, usize)> {
+        match self.children().len() {
+            0 | 1 => None,
+            count => Some((self.branch % count + 1, count)),
+        }
+    }
+}
+
+/// An input the engine can be driven with.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum ReplayEvent {
+    Step(Controls),
+    Tick,
+    Lock,
+    Second, // a second of play
+    PreviewCount(usize),
+    LockResetCap(usize),
+    GravityFloor(u64),
+    SoftDropRate(u64),
+    RotationSystem(RotationSystem),
+    HoldKeepsRotation(bool),
+    Randomizer(Randomizer),
+    StartLevel(usize),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct ReplayEntry {
+    at: f64, // ms since the game started
+    event: ReplayEvent,
+}
+
+/// Everything needed to play a game again: how it was set up, seed included,
+/// and every input in the order it happened.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Replay {
+    settings: Settings,
+    entries: Vec<ReplayEntry>,
+}
+
+impl Replay {
+    pub fn new(settings: Settings) -> Replay {
+        Replay {
+            settings,
+            entries: Vec::new(),
+        }
+    }
+
+    /// How the game was set up, its seed included.
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Whether playing it again deals the same pieces, which a custom
+    /// `PieceSource` doesn't.
+    pub fn can_replay(&self) -> bool {
+        !self.settings.custom_pieces
+    }
+
+    pub(crate) fn set_custom_pieces(&mut self) {
+        self.settings.custom_pieces = true;
+    }
+
+    pub fn record(&mut self, at: f64, event: ReplayEvent) {
+        self.entries.push(ReplayEntry { at, event });
+    }
+
+    /// Time of the last recorded input.
+    pub fn duration(&self) -> f64 {
+        self.entries.last().map_or(0.0, |entry| entry.at)
+    }
+}
+
+/// The score a replay plays out to, for checking the one claimed for it,
+/// None if it can't be played again.
+pub fn verify_replay(replay: &Replay) -> Option<usize> {
+    if !replay.can_replay() {
+        return None;
+    }
+    Some(Game::from_replay(replay.clone()).state.game_status.score)
+}
+
+/// Plays a replay back into a game at the pace it was recorded in, for
+/// watching rather than re-running it in one go like `Game::from_replay`.
+pub struct Playback {
+    replay: Replay,
+    next: usize, // the first entry not played yet
+}
+
+impl Playback {
+    /// The playback and the fresh game it plays into.
+    pub fn new(replay: Replay) -> (Playback, Game) {
+        let game = Game::new(replay.settings.clone());
+        (Playback { replay, next: 0 }, game)
+    }
+
+    /// Plays every input recorded up to `at` ms into `game`, false if there
+    /// was none. The game gets the whole replay once it is played out.
+    pub fn advance(&mut self, game: &mut Game, at: f64) -> bool {
+        let start = self.next;
+        while let Some(entry) = self.replay.entries.get(self.next).filter(|entry| entry.at <= at) {
+            game.play(entry.event.clone());
+            self.next += 1;
+        }
+        if self.is_finished() {
+            game.state.replay = self.replay.clone();
+        }
+        self.next > start
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next == self.replay.entries.len()
+    }
+}
+
+/// Two games side by side, for local versus. Rows one player clears are
+/// sent to the other as garbage, which rises under their stack once a piece
+/// of theirs locks without clearing any.
+pub struct Versus {
+    pub(crate) games: [Game; 2],
+    pub(crate) pending_garbage: [usize; 2], // rows on their way to each player
+    gravity_acc: [f64; 2],
+    grounded: [bool; 2], // landed at the last row of gravity, locks at the next
+}
+
+impl Versus {
+    /// An endless Marathon on each board, both dealt the same pieces.
+    pub fn new(settings: Settings) -> Versus {
+        let settings = Settings {
+            seed: Some(settings.seed.unwrap_or_else(rand::random)),
+            mode: GameMode::Marathon,
+            marathon_goal: 0,
+            puzzle: None,
+            ..settings
+        };
+        Versus {
+            games: [Game::new(settings.clone()), Game::new(settings)],
+            pending_garbage: [0, 0],
+            gravity_acc: [0.0, 0.0],
+            grounded: [false, false],
+        }
+    }
+
+    /// Plays an input on the board of `player`, 0 or 1.
+    pub fn play(&mut self, player: usize, event: ReplayEvent) -> StepOutcome {
+        if self.winner().is_some() {
+            return StepOutcome::Blocked;
+        }
+        let outcome = self.games[player].play(event);
+        if let StepOutcome::Locked { rows_cleared } = outcome {
+            self.grounded[player] = false;
+            let sent = GARBAGE_SENT[rows_cleared.min(GARBAGE_SENT.len() - 1)];
+            // a clear cancels garbage still on its way in before sending any
+            let cancelled = sent.min(self.pending_garbage[player]);
+            self.pending_garbage[player] -= cancelled;
+            self.pending_garbage[1 - player] += sent - cancelled;
+            if rows_cleared == 0 {
+                self.raise_garbage(player);
+            }
+        }
+        outcome
+    }
+
+    fn raise_garbage(&mut self, player: usize) {
+        let rows = std::mem::take(&mut self.pending_garbage[player]);
+        if rows > 0 {
+            let state = &mut self.games[player].state;
+            let hole_col = state.rng.gen_range(0, state.stage.n_cols);
+            state.add_garbage(rows, hole_col);
+        }
+    }
+
+    /// Runs `elapsed` ms of gravity on both boards, false if nothing fell.
+    /// There is no lock delay timer, a landed piece locks at the next row.
+    pub fn fall(&mut self, elapsed: f64) -> bool {
+        let mut fell = false;
+        for player in 0..2 {
+            self.gravity_acc[player] += elapsed;
+            while self.winner().is_none()
+                && self.games[player].state.take_gravity_row(&mut self.gravity_acc[player])
+            {
+                fell = true;
+                match self.play(player, ReplayEvent::Tick) {
+                    StepOutcome::Grounded if self.grounded[player] => {
+                        self.play(player, ReplayEvent::Lock);
+                    }
+                    StepOutcome::Grounded => self.grounded[player] = true,
+                    _ => self.grounded[player] = false,
+                }
+            }
+        }
+        fell
+    }
+
+    /// The player still standing once the other has topped out.
+    pub fn winner(&self) -> Option<usize> {
+        match (self.games[0].is_over(), self.games[1].is_over()) {
+            (true, false) => Some(1),
+            (false, true) => Some(0),
+            _ => None,
+        }
+    }
+}
+
+/// The rules of the game, free of any yew service, `Model` only drives it
+/// and renders its `State`.
+pub struct Game {
+    pub(crate) state: State,
+}
+
+// rotation states count from each piece's spawn orientation in PIECES, a
+// counterclockwise turn kicks the opposite way of the clockwise turn it undoes.
+// Sideways nudges the table lacks are tried last, so wider custom pieces can
+// still turn against a wall.
+fn get_kicks(
+    rotation_system: RotationSystem,
+    piece_type: &PieceType,
+    rotation: usize,
+    quarter_turns: usize,
+) -> Vec<(isize, isize)> {
+    if rotation_system == RotationSystem::Simple {
+        return SIMPLE_KICKS.to_vec();
+    }
+    let kicks = match piece_type {
+        PieceType::I => &I_KICKS,
+        _ => &JLSTZ_KICKS,
+    };
+    let mut kicks: Vec<(isize, isize)> = match quarter_turns % 4 {
+        1 => kicks[rotation % 4].to_vec(),
+        2 => FLIP_KICKS[rotation % 4].to_vec(),
+        3 => kicks[(rotation + 3) % 4].iter().map(|&(dx, dy)| (-dx, -dy)).collect(),
+        _ => return vec![(0, 0)],
+    };
+    for nudge in WALL_NUDGES.iter() {
+        if !kicks.contains(nudge) {
+            kicks.push(*nudge);
+        }
+    }
+    kicks
+}
+
+// clockwise quarter turns a rotate control makes, 3 being a counterclockwise turn
+fn quarter_turns(control: &Controls) -> usize {
+    match control {
+        Controls::RotateCCW => 3,
+        Controls::Rotate180 => 2,
+        _ => 1,
+    }
+}
+
+impl Game {
+    pub fn new(settings: Settings) -> Game {
+        Game {
+            state: State::new(settings),
+        }
+    }
+
+    /// A game dealt from custom piece shapes, keyed by piece type name.
+    /// Fails on a shape that isn't a square grid of its own piece type.
+    pub fn with_pieces(settings: Settings, pieces: HashMap<String, Piece>) -> Result<Game, String> {
+        let pieces = PieceSet::new(pieces)?;
+        Ok(Game {
+            state: State::with_pieces(settings, pieces),
+        })
+    }
+
+    /// Runs the inputs of a replay against a game set up the same way.
+    pub fn from_replay(replay: Replay) -> Game {
+        let mut game = Game::new(replay.settings.clone());
+        for entry in &replay.entries {
+            game.play(entry.event.clone());
+        }
+        // the clock picks up at the last recorded input
+        game.state.played.played = replay.duration();
+        game.state.replay = replay;
+        game
+    }
+
+    pub fn play(&mut self, event: ReplayEvent) -> StepOutcome {
+        match event {
+            ReplayEvent::Step(control) => self.step(control),
+            ReplayEvent::Tick => self.tick(),
+            ReplayEvent::Lock => self.lock(),
+            ReplayEvent::Second => self.clock_tick(),
+            ReplayEvent::PreviewCount(preview_count) => {
+                self.state.set_preview_count(preview_count);
+                StepOutcome::Moved
+            }
+            ReplayEvent::LockResetCap(lock_reset_cap) => {
+                self.state.lock_reset_cap = lock_reset_cap;
+                StepOutcome::Moved
+            }
+            ReplayEvent::GravityFloor(gravity_floor_ms) => {
+                self.state.gravity_floor_ms = gravity_floor_ms;
+                StepOutcome::Moved
+            }
+            ReplayEvent::SoftDropRate(soft_drop_ms) => {
+                self.state.soft_drop_ms = soft_drop_ms;
+                StepOutcome::Moved
+            }
+            ReplayEvent::RotationSystem(rotation_system) => {
+                self.state.rotation_system = rotation_system;
+                StepOutcome::Moved
+            }
+            ReplayEvent::HoldKeepsRotation(hold_keeps_rotation) => {
+                self.state.hold_keeps_rotation = hold_keeps_rotation;
+                StepOutcome::Moved
+            }
+            ReplayEvent::Randomizer(randomizer) => {
+                self.state.set_randomizer(randomizer);
+                StepOutcome::Moved
+            }
+            ReplayEvent::StartLevel(start_level) => {
+                self.state.set_start_level(start_level);
+                StepOutcome::Moved
+            }
+        }
+    }
+
+    /// Counts a second of play, ending a timed game when its clock runs out.
+    pub fn clock_tick(&mut self) -> StepOutcome {
+        if self.is_over() || self.state.paused {
+            return StepOutcome::Blocked;
+        }
+        self.state.elapsed += 1;
+        if self.state.mode == GameMode::Cheese && self.state.elapsed.is_multiple_of(CHEESE_INTERVAL) {
+            let hole_col = self.state.rng.gen_range(0, self.state.stage.n_cols);
+            self.state.add_garbage(1, hole_col);
+            if self.is_over() {
+                return StepOutcome::GameOver;
+            }
+        }
+        match self.state.time_left {
+            Some(seconds) if seconds <= 1 => {
+                self.state.time_left = Some(0);
+                self.state.game_over();
+                StepOutcome::GameOver
+            }
+            Some(seconds) => {
+                self.state.time_left = Some(seconds - 1);
+                StepOutcome::Moved
+            }
+            None => StepOutcome::Moved,
+        }
+    }
+
+    /// Applies a single player input, `Controls::Down` being one row of gravity.
+    pub fn step(&mut self, control: Controls) -> StepOutcome {
+        // nothing moves once the stack has topped out
+        if self.is_over() {
+            return StepOutcome::Blocked;
+        }
+        if self.state.paused && control != Controls::Pause {
+            return StepOutcome::Blocked;
+        }
+        match control {
+            Controls::Left => {
+                if self.is_move_allowed(Controls::Left, None) {
+                    self.state.player.position.x -= 1;
+                    self.state.player.last_kick = None;
+                    self.state.announce("Moved left");
+                    self.after_move()
+                } else {
+                    self.state.announce("Blocked");
+                    StepOutcome::Blocked
+                }
+            }
+            Controls::Right => {
+                if self.is_move_allowed(Controls::Right, None) {
+                    self.state.player.position.x += 1;
+                    self.state.player.last_kick = None;
+                    self.state.announce("Moved right");
+                    self.after_move()
+                } else {
+                    self.state.announce("Blocked");
+                    StepOutcome::Blocked
+                }
+            }
+            Controls::Bottom => {
+                let start_y = self.state.player.position.y;
+                while self.is_move_allowed(Controls::Down, None) {
+                    self.state.player.position.y += 1
+                }
+                if self.state.player.position.y > start_y {
+                    self.state.player.last_kick = None;
+                }
+                if self.state.player.position.y > 0 {
+                    // a new drop replaces whatever is left of the last trail
+                    self.state.trail = if self.state.trail_enabled {
+                        Trail::from_drop(&self.state.player, start_y)
+                    } else {
+                        None
+                    };
+                    let distance = (self.state.player.position.y - start_y) as usize;
+                    self.state.add_drop_points(HARD_DROP_POINTS * distance);
+                }
+                self.lock_piece()
+            }
+            // unlike gravity, a soft drop scores and never locks the piece itself
+            Controls::SoftDrop => {
+                if self.is_move_allowed(Controls::Down, None) {
+                    self.state.player.position.y += 1;
+                    self.state.player.last_kick = None;
+                    self.state.add_drop_points(SOFT_DROP_POINTS);
+                    StepOutcome::Moved
+                } else {
+                    StepOutcome::Blocked
+                }
+            }
+            Controls::Down => {
+                if self.is_move_allowed(Controls::Down, None) {
+                    self.state.player.position.y += 1;
+                    self.state.player.last_kick = None;
+                    StepOutcome::Moved
+                } else if self.state.player.position.y <= 0 || self.is_out_of_resets() {
+                    // out of resets, gravity locks it without waiting for the delay
+                    self.lock_piece()
+                } else {
+                    StepOutcome::Grounded
+                }
+            }
+            Controls::Rotate | Controls::RotateCCW | Controls::Rotate180 => {
+                // nothing on the board changes, so the lock delay carries on
+                let in_place = self.state.turns_in_place(quarter_turns(&control));
+                if self.try_rotate(control) {
+                    self.state.announce("Rotated");
+                    if in_place {
+                        StepOutcome::Moved
+                    } else {
+                        self.after_move()
+                    }
+                } else {
+                    self.state.announce("Blocked");
+                    StepOutcome::Blocked
+                }
+            }
+            Controls::Hold => {
+                if self.state.hold_piece() {
+                    self.state.announce("Held");
+                    if self.state.is_piece_blocked() {
+                        self.state.game_over();
+                        return StepOutcome::GameOver;
+                    }
+                    StepOutcome::Moved
+                } else {
+                    self.state.announce("Hold used");
+                    StepOutcome::Blocked
+                }
+            }
+            Controls::Undo => {
+                if self.is_move_allowed(Controls::Undo, None) {
+                    self.state.undo();
+                    self.state.announce("Undone");
+                    StepOutcome::Moved
+                } else {
+                    StepOutcome::Blocked
+                }
+            }
+            Controls::Redo => {
+                if self.is_move_allowed(Controls::Redo, None) {
+                    self.state.redo();
+                    self.state.announce("Redone");
+                    StepOutcome::Moved
+                } else {
+                    StepOutcome::Blocked
+                }
+            }
+            Controls::Branch => {
+                if self.is_move_allowed(Controls::Branch, None) {
+                    self.state.history.branch += 1;
+                    if let Some((branch, count)) = self.state.history.branches() {
+                        self.state.announce(&format!("Branch {} of {}", branch, count));
+                    }
+                    StepOutcome::Moved
+                } else {
+                    StepOutcome::Blocked
+                }
+            }
+            Controls::Pause => {
+                self.state.paused = !self.state.paused;
+                if self.state.paused {
+                    StepOutcome::Paused
+                } else {
+                    StepOutcome::Resumed
+                }
+            }
+        }
+    }
+
+    /// One gravity step.
+    pub fn tick(&mut self) -> StepOutcome {
+        self.step(Controls::Down)
+    }
+
+    /// Ends the lock delay, locking the piece if it is still grounded.
+    pub fn lock(&mut self) -> StepOutcome {
+        if self.is_over() || self.state.paused {
+            StepOutcome::Blocked
+        } else if self.is_move_allowed(Controls::Down, None) {
+            // slid off the ledge it was resting on, gravity takes over again
+            StepOutcome::Moved
+        } else {
+            self.lock_piece()
+        }
+    }
+
+    // a move that leaves the piece grounded restarts its lock delay, a limited
+    // number of times so it can't be stalled forever
+    fn after_move(&mut self) -> StepOutcome {
+        if !self.is_move_allowed(Controls::Down, None)
+            && self.state.lock_resets < self.state.lock_reset_cap
+        {
+            self.state.lock_resets += 1;
+            StepOutcome::LockReset
+        } else {
+            StepOutcome::Moved
+        }
+    }
+
+    fn is_out_of_resets(&self) -> bool {
+        self.state.lock_resets >= self.state.lock_reset_cap
+    }
+
+    /// Whether the piece is grounded with no lock delay restarts left, so it
+    /// locks at the next gravity step or when its delay runs out.
+    pub fn is_force_locking(&self) -> bool {
+        self.is_out_of_resets() && !self.is_move_allowed(Controls::Down, None)
+    }
+
+    pub fn snapshot(&self) -> &State {
+        &self.state
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.state.game_status.game_over
+    }
+
+    /// Row the current piece would land on if hard dropped.
+    pub fn ghost_y(&self) -> isize {
+        let Position { x, mut y } = self.state.player.position;
+        while self.is_move_allowed(Controls::Down, Some(Position { x, y })) {
+            y += 1;
+        }
+        y
+    }
+
+    // locks the piece where it stands and clears rows, unless it never made it
+    // onto the stage, which tops out the game
+    fn lock_piece(&mut self) -> StepOutcome {
+        if self.state.player.position.y <= 0 {
+            self.state.game_over();
+            return StepOutcome::GameOver;
+        }
+        let spin = self.t_spin();
+        if self.state.mode == GameMode::Practice {
+            self.state.take_snapshot();
+        }
+        if self.state.player.inputs > self.state.finesse_minimum() {
+            self.state.game_status.finesse_faults += 1;
+        }
+        self.state.add_player_piece_stage();
+
+        let rows = self.state.get_completed_rows();
+        let rows_cleared = rows.len();
+        if rows_cleared > 0 {
+            self.state.remove_rows(rows);
+        }
+        self.state.perfect_clear = rows_cleared > 0 && self.state.is_board_empty();
+        self.state.update_game_state(rows_cleared, spin);
+        if let Some(lines_goal) = self.state.lines_goal {
+            if self.state.game_status.rows_cleared >= lines_goal {
+                // score and level stay as they were at the winning clear
+                self.state.game_status.game_won = true;
+                self.state.game_over();
+                return StepOutcome::GameOver;
+            }
+        }
+        if let Some(pieces_left) = self.state.pieces_left {
+            let pieces_left = pieces_left.saturating_sub(1);
+            self.state.pieces_left = Some(pieces_left);
+            // a puzzle is solved by the clear that takes its last garbage block
+            if !self.state.has_garbage() {
+                self.state.game_status.game_won = true;
+                self.state.game_over();
+                return StepOutcome::GameOver;
+            } else if pieces_left == 0 {
+                self.state.game_over();
+                return StepOutcome::GameOver;
+            }
+        }
+        if self.is_over() {
+            return StepOutcome::GameOver;
+        }
+        StepOutcome::Locked { rows_cleared }
+    }
+
+    // a T that rotated into place with three of the four corners around its
+    // center blocked, a full T-spin if both corners it points at are blocked or
+    // the last SRS quarter turn kick lifted it there
+    fn t_spin(&self) -> Option<TSpin> {
+        let player = &self.state.player;
+        let kick = player.last_kick?;
+        if player.piece_type != PieceType::T {
+            return None;
+        }
+        let Position { x, y } = player.position;
+        let stage = &self.state.stage;
+        // clockwise from the top left, the T points between corners
+        // rotation and rotation + 1
+        let blocked: Vec<bool> = [(0, 0), (0, 2), (2, 2), (2, 0)]
+            .iter()
+            .map(|&(n_row, n_col)| {
+                let row = y + n_row;
+                let col = x + n_col;
+                if row < 0 {
+                    false
+                } else if row >= stage.n_rows as isize || col < 0 || col >= stage.n_cols as isize {
+                    true
+                } else {
+                    stage.get(row as usize, col as usize) != PieceType::E.as_ref()
+                }
+            })
+            .collect();
+        if blocked.iter().filter(|&&corner| corner).count() < 3 {
+            return None;
+        }
+        let rotation = player.rotation % 4;
+        let lifted = player.quarter_kick && kick == LAST_KICK;
+        if (blocked[rotation] && blocked[(rotation + 1) % 4]) || lifted {
+            Some(TSpin::Full)
+        } else {
+            Some(TSpin::Mini)
+        }
+    }
+
+    fn try_rotate(&mut self, control: Controls) -> bool {
+        let Position { x, y } = self.state.player.position;
+        let quarter_turns = quarter_turns(&control);
+        // a piece that looks the same turned, like the O, only changes its
+        // rotation state, it never moves and never kicks off a wall
+        if self.state.turns_in_place(quarter_turns) {
+            let rotation = self.state.player.rotation;
+            self.state.player.rotation = (rotation + quarter_turns) % 4;
+            self.state.player.last_kick = Some(0);
+            self.state.player.quarter_kick = false;
+            return true;
+        }
+        let Player { piece_type, rotation, .. } = &self.state.player;
+        let kicks = get_kicks(self.state.rotation_system, piece_type, *rotation, quarter_turns);
+        for (kick, (dx, dy)) in kicks.into_iter().enumerate() {
+            let position = Position { x: x + dx, y: y + dy };
+            if self.is_move_allowed(control.clone(), Some(position)) {
+                match quarter_turns {
+                    3 => self.state.rotate_player_piece_ccw(),
+                    2 => self.state.rotate_player_piece_180(),
+                    _ => self.state.rotate_player_piece(),
+                }
+                self.state.player.position = Position { x: x + dx, y: y + dy };
+                self.state.player.last_kick = Some(kick);
+                // only SRS quarter turns index their table's kicks before the nudges
+                self.state.player.quarter_kick = self.state.rotation_system == RotationSystem::Srs
+                    && quarter_turns % 2 == 1;
+                // whichever kick got picked, the piece can't overlap locked blocks
+                let offsets = self.state.player_offsets(self.state.player.rotation);
+                debug_assert!(self.state.is_position_empty(x + dx, y + dy, offsets));
+                debug_assert!(self.state.is_player_position_valid(x + dx, y + dy, offsets));
+                return true;
+            }
+        }
+        false
+    }
+
+    // quarter_turns clockwise, 3 being a counterclockwise turn
+    fn is_rotate_allowed(&self, x: isize, y: isize, quarter_turns: usize) -> bool {
+        let rotated = self.state.player_offsets(self.state.player.rotation + quarter_turns);
+
+        self.state.is_position_empty(x, y, rotated) && self.state.is_player_position_valid(x, y, rotated)
+    }
+
+    fn is_move_allowed(&self, control: Controls, position: Option<Position>) -> bool {
+        let x: isize;
+        let y: isize;
+
+        if let Some(position) = position {
+            x = position.x;
+            y = position.y;
+        } else {
+            x = self.state.player.position.x;
+            y = self.state.player.position.y;
+        }
+
+        let offsets = self.state.player_offsets(self.state.player.rotation);
+
+        match control {
+            Controls::Left => {
+                self.state.is_player_position_valid(x - 1, y, offsets)
+                    && self.state.is_position_empty(x - 1, y, offsets)
+            }
+            Controls::Right => {
+                self.state.is_player_position_valid(x + 1, y, offsets)
+                    && self.state.is_position_empty(x + 1, y, offsets)
+            }
+            Controls::Bottom | Controls::Down | Controls::SoftDrop => {
+                self.state.is_player_position_valid(x, y + 1, offsets)
+                    && self.state.is_position_empty(x, y + 1, offsets)
+            }
+            Controls::Rotate => self.is_rotate_allowed(x, y, 1),
+            Controls::RotateCCW => self.is_rotate_allowed(x, y, 3),
+            Controls::Rotate180 => self.is_rotate_allowed(x, y, 2),
+            Controls::Hold => !self.state.hold_used,
+            Controls::Pause => true,
+            Controls::Undo => {
+                self.state.mode == GameMode::Practice && self.state.history.undo_target().is_some()
+            }
+            Controls::Redo => {
+                self.state.mode == GameMode::Practice && self.state.history.redo_target().is_some()
+            }
+            Controls::Branch => {
+                self.state.mode == GameMode::Practice && self.state.history.branches().is_some()
+            }
+        }
+    }
+}
+
+impl State {
+    /// Whether none of `offsets`, placed at `x`, `y`, cover a locked block.
+    /// Cells off the stage count as empty, see `is_player_position_valid`.
+    pub fn is_position_empty(&self, x: isize, y: isize, offsets: &[(usize, usize)]) -> bool {
+        let stage_rows = self.stage.n_rows as isize;
+        let stage_cols = self.stage.n_cols as isize;
+
+        offsets.iter().all(|&(n_row, n_col)| {
+            let row = n_row as isize + y;
+            let col = n_col as isize + x;
+
+            row < 0
+                || row >= stage_rows
+                || col < 0
+                || col >= stage_cols
+                || self.stage.get(row as usize, col as usize) == PieceType::E.as_ref()
+        })
+    }
+
+    /// Whether `offsets`, placed at `x`, `y`, stay between the walls and
+    /// above the floor. Rows above the stage are allowed.
+    pub fn is_player_position_valid(&self, x: isize, y: isize, offsets: &[(usize, usize)]) -> bool {
+        let stage_rows = self.stage.n_rows as isize;
+        let stage_cols = self.stage.n_cols as isize;
+
+        // check if piece ouside left, right or low border of stage
+        offsets.iter().all(|&(n_row, n_col)| {
+            let row = n_row as isize + y;
+            let col = n_col as isize + x;
+
+            col >= 0 && col < stage_cols && row < stage_rows
+        })
+    }
+
+    /// The rows of the stage with no empty cell, top to bottom.
+    pub fn get_completed_rows(&self) -> Vec<usize> {
+        let mut full_rows: Vec<usize> = Vec::new();
+        let Vec2D {
+            n_rows: stage_rows,
+            n_cols: stage_cols,
+            ..
+        } = self.stage.clone();
+        let stage_rows = stage_rows as isize;
+        let stage_cols = stage_cols as isize;
+
+        for n_row in 0..stage_rows {
+            let mut empty_cell_exists = false;
+            for n_col in 0..stage_cols {
+                if self.stage.get(n_row as usize, n_col as usize) == PieceType::E.as_ref() {
+                    empty_cell_exists = true;
+                }
+            }
+
+            if !empty_cell_exists {
+                full_rows.push(n_row as usize);
+            }
+        }
+
+        full_rows
+    }
+
+    fn add_player_piece_stage(&mut self) {
+        let Vec2D {
+            n_rows: stage_rows,
+            n_cols: stage_cols,
+            ..
+        } = self.stage.clone();
+        let Vec2D {
+            n_rows: player_rows,
+            n_cols: player_cols,
+            ..
+        } = self.player.piece_shape.clone();
+        let Position { x, y } = self.player.position;
+        let player_rows = player_rows as isize;
+        let player_cols = player_cols as isize;
+        let stage_rows = stage_rows as isize;
+        let stage_cols = stage_cols as isize;
+
+        for n_row in 0..player_rows {
+            for n_col in 0..player_cols {
+                let row = n_row + y;
+                let col = n_col + x;
+
+                if row < 0 || row >= stage_rows || col < 0 || col >= stage_cols {
+                    info!("nope");
+                } else {
+                    let cell = self.player.piece_shape.get(n_row as usize, n_col as usize);
+                    if cell != PieceType::E.as_ref() {
+                        self.stage.set(row as usize, col as usize, cell);
+                    }
+                }
+            }
+        }
+        let next_piece = self.next_piece();
+        let (random_piece, piece_shape) = self.pieces.spawn(next_piece);
+        self.player.piece_type = random_piece;
+        self.player.piece_shape = piece_shape;
+        self.player.rotation = 0;
+        self.player.last_kick = None;
+        self.player.inputs = 0;
+        self.move_to_spawn();
+        self.hold_used = false;
+        self.lock_resets = 0;
+        self.record_spawn();
+        self.announce("Locked");
+        // block out, the new piece has nowhere to spawn
+        if self.is_piece_blocked() {
+            self.game_over();
+        }
+    }
+
+    // grows the queue from the piece source or drops its far end, the next
+    // piece always stays
+    fn set_preview_count(&mut self, preview_count: usize) {
+        self.preview_count = preview_count;
+        while self.next_queue.len() < preview_count {
+            self.next_queue.push(self.piece_source.next());
+        }
+        self.next_queue.truncate(preview_count);
+    }
+
+    // takes the front of the preview queue and tops it up from the piece source
+    fn next_piece(&mut self) -> PieceType {
+        self.next_queue.push(self.piece_source.next());
+        self.next_queue.remove(0)
+    }
+
+    // swaps the active piece with the held one, or stashes it and pulls the
+    // next piece when nothing is held yet
+    fn hold_piece(&mut self) -> bool {
+        if self.hold_used {
+            return false;
+        }
+        let (next_piece, rotation, dealt) = match self.held_piece.take() {
+            Some(held_piece) => (held_piece, self.held_rotation, false),
+            None => (self.next_piece(), 0, true),
+        };
+        self.held_piece = Some(self.player.piece_type.clone());
+        self.held_rotation = if self.hold_keeps_rotation {
+            self.player.rotation % 4
+        } else {
+            0
+        };
+
+        // back to the spawn position, whatever its grid size, and to the
+        // spawn orientation unless hold keeps the one it went in with
+        let (piece_type, mut piece_shape) = self.pieces.spawn(next_piece);
+        for _ in 0..rotation {
+            piece_shape = piece_shape.rotated();
+        }
+        self.player.piece_type = piece_type;
+        self.player.piece_shape = piece_shape;
+        self.player.rotation = rotation;
+        self.player.last_kick = None;
+        self.player.inputs = 0;
+        self.move_to_spawn();
+        self.hold_used = true;
+        self.lock_resets = 0;
+        if dealt {
+            self.record_spawn();
+        }
+        true
+    }
+
+    fn update_game_state(&mut self, rows_cleared: usize, spin: Option<TSpin>) {
+        let score: usize = match (spin, rows_cleared) {
+            (None, 0) => 0,
+            (None, 1) => 40 * self.game_status.level,
+            (None, 2) => 100 * self.game_status.level,
+            (None, 3) => 300 * self.game_status.level,
+            (None, _) => 1200 * self.game_status.level,
+            (Some(TSpin::Mini), 0) => 100 * self.game_status.level,
+            (Some(TSpin::Mini), 1) => 200 * self.game_status.level,
+            (Some(TSpin::Mini), _) => 400 * self.game_status.level,
+            (Some(TSpin::Full), 0) => 400 * self.game_status.level,
+            (Some(TSpin::Full), 1) => 800 * self.game_status.level,
+            (Some(TSpin::Full), 2) => 1200 * self.game_status.level,
+            (Some(TSpin::Full), _) => 1600 * self.game_status.level,
+        };
+        // difficult clears in a row score half as much again, though only on
+        // the clear itself and not on any perfect clear bonus
+        let difficult = rows_cleared >= 4 || spin.is_some();
+        let score = if rows_cleared > 0 && difficult && self.game_status.back_to_back {
+            score * 3 / 2
+        } else {
+            score
+        };
+        let score = if self.perfect_clear {
+            let bonus = match rows_cleared {
+                1 => 3000,
+                2 => 4000,
+                3 => 5000,
+                _ => 6000,
+            };
+            score + bonus * self.game_status.level
+        } else {
+            score
+        };
+        if rows_cleared > 0 {
+            // consecutive clearing pieces build a combo
+            let combo = self.game_status.combo + 1;
+            let combo_bonus = if combo > 0 {
+                50 * combo as usize * self.game_status.level
+            } else {
+                0
+            };
+            self.last_clear = classify_clear(rows_cleared, spin);
+            let rows_cleared = self.game_status.rows_cleared + rows_cleared;
+            let level: usize = ((rows_cleared / 10) + 1).max(self.game_status.start_level);
+            self.game_status = GameStatus {
+                level,
+                start_level: self.game_status.start_level,
+                score: self.game_status.score + score + combo_bonus,
+                rows_cleared,
+                combo,
+                back_to_back: difficult,
+                finesse_faults: self.game_status.finesse_faults,
+                game_over: self.game_status.game_over,
+                game_won: self.game_status.game_won,
+            }
+        } else {
+            // a T-spin without lines still scores, but doesn't break the
+            // back-to-back chain
+            self.game_status.score += score;
+            self.game_status.combo = -1;
+        }
+    }
+
+    fn add_drop_points(&mut self, points: usize) {
+        self.game_status.score += points;
+    }
+
+    fn is_board_empty(&self) -> bool {
+        self.stage.data.iter().all(|cell| *cell == PieceType::E)
+    }
+
+    // drops the rows above each cleared one down in a single pass, topping the
+    // stage up with empty rows
+    fn remove_rows(&mut self, rows: Vec<usize>) {
+        let n_cols = self.stage.n_cols;
+        let empty_cells = rows.len().min(self.stage.n_rows) * n_cols;
+        let mut data = vec![PieceType::E; empty_cells];
+        for (row, cells) in self.stage.data.chunks(n_cols).enumerate() {
+            if !rows.contains(&row) {
+                data.extend_from_slice(cells);
+            }
+        }
+        self.stage.data = data;
+    }
+
+    /// Pushes the stack up `n` rows and fills the bottom ones with garbage,
+    /// each with a hole at `hole_col`. Blocks pushed off the top of the
+    /// stage or into the falling piece top out.
+    pub fn add_garbage(&mut self, n: usize, hole_col: usize) {
+        let Vec2D { n_rows, n_cols, .. } = self.stage.clone();
+        let n = n.min(n_rows);
+        let stage = self.stage.clone();
+        let topped_out = stage.occupied().iter().any(|&(row, _)| row < n);
+
+        for row in 0..n_rows {
+            for col in 0..n_cols {
+                let piece = if row + n < n_rows {
+                    stage.get(row + n, col)
+                } else if col == hole_col {
+                    PieceType::E.as_ref()
+                } else {
+                    PieceType::G.as_ref()
+                };
+                self.stage.set(row, col, piece);
+            }
+        }
+
+        if topped_out || self.is_piece_blocked() {
+            self.game_over();
+        }
+    }
+
+    // the fewest presses that get the falling piece where it is: one turn for
+    // any orientation, then taps, or a DAS to the wall and taps back from it.
+    // Tucks and spins aren't accounted for.
+    fn finesse_minimum(&self) -> usize {
+        let offsets = self.player_offsets(self.player.rotation);
+        let x = self.player.position.x;
+        let first_col = x + offsets.iter().map(|&(_, col)| col).min().unwrap_or(0) as isize;
+        let last_col = x + offsets.iter().map(|&(_, col)| col).max().unwrap_or(0) as isize;
+        let to_left_wall = first_col;
+        let to_right_wall = self.stage.n_cols as isize - 1 - last_col;
+        let dx = x - spawn_position(self.stage.n_cols, 0).x;
+        let shifts = if dx < 0 {
+            (-dx).min(1 + to_left_wall)
+        } else {
+            dx.min(1 + to_right_wall)
+        };
+        let turns = if self.player.rotation == 0 { 0 } else { 1 };
+        turns + shifts as usize
+    }
+
+    fn take_snapshot(&mut self) {
+        let snapshot = self.snapshot();
+        self.history.push(snapshot);
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            stage: self.stage.clone(),
+            player: self.player.clone(),
+            game_status: self.game_status.clone(),
+            next_queue: self.next_queue.clone(),
+            held_piece: self.held_piece.clone(),
+            held_rotation: self.held_rotation,
+            hold_used: self.hold_used,
+            droughts: self.droughts.clone(),
+            piece_counts: self.piece_counts.clone(),
+        }
+    }
+
+    // back to just before the last piece locked, or the position before that
+    // one if the game is still at it. The piece source carries on where it is,
+    // so the pieces dealt after the restored queue may differ.
+    fn undo(&mut self) {
+        if !self.history.at_current {
+            // where the game got to, for redo to come back to
+            self.take_snapshot();
+            self.history.at_current = true;
+        }
+        if let Some(node) = self.history.undo_target() {
+            self.go_to(node);
+        }
+    }
+
+    fn redo(&mut self) {
+        if let Some(node) = self.history.redo_target() {
+            self.go_to(node);
+        }
+    }
+
+    // the game is at that position afterwards, redo starts from its first branch
+    fn go_to(&mut self, node: usize) {
+        self.history.current = Some(node);
+        self.history.at_current = true;
+        self.history.branch = 0;
+        let snapshot = self.history.nodes[node].snapshot.clone();
+        self.stage = snapshot.stage;
+        self.player = snapshot.player;
+        self.game_status = snapshot.game_status;
+        self.next_queue = snapshot.next_queue;
+        self.held_piece = snapshot.held_piece;
+        self.held_rotation = snapshot.held_rotation;
+        self.hold_used = snapshot.hold_used;
+        self.droughts = snapshot.droughts;
+        self.piece_counts = snapshot.piece_counts;
+        self.last_clear = None;
+        self.perfect_clear = false;
+        self.trail = None;
+        self.lock_resets = 0;
+    }
+
+    fn player_offsets(&self, rotation: usize) -> &[(usize, usize)] {
+        self.pieces.offsets(&self.player.piece_type, rotation)
+    }
+
+    // whether the piece covers the same cells after `quarter_turns`
+    fn turns_in_place(&self, quarter_turns: usize) -> bool {
+        let rotation = self.player.rotation;
+        self.player_offsets(rotation + quarter_turns) == self.player_offsets(rotation)
+    }
+
+    // back to the spawn position, lifted into the safety rows above it for as
+    // long as the stack is in the way
+    fn move_to_spawn(&mut self) {
+        let safety_rows = self.replay.settings().safety_rows;
+        self.player.position = spawn_position(self.stage.n_cols, safety_rows);
+        while self.player.position.y > 0 && self.is_piece_blocked() {
+            self.player.position.y -= 1;
+        }
+    }
+
+    // the falling piece overlaps locked blocks, which only happens when it
+    // spawns into them or garbage pushes the stack into it
+    fn is_piece_blocked(&self) -> bool {
+        let Position { x, y } = self.player.position;
+        self.stage
+            .occupied()
+            .iter()
+            .any(|&(row, col)| self.player.fills(x, y, row as isize, col as isize))
+    }
+
+    fn game_over(&mut self) {
+        self.game_status.game_over = true;
+        if self.blind_mode {
+            self.announcement = "Game over".into();
+        }
+    }
+
+    fn rotate_player_piece(&mut self) {
+        self.player.piece_shape = self.player.piece_shape.rotated();
+        self.player.rotation = (self.player.rotation + 1) % 4;
+    }
+
+    fn rotate_player_piece_ccw(&mut self) {
+        self.player.piece_shape = self.player.piece_shape.rotated_ccw();
+        self.player.rotation = (self.player.rotation + 3) % 4;
+    }
+
+    fn rotate_player_piece_180(&mut self) {
+        self.player.piece_shape = self.player.piece_shape.rotated().rotated();
+        self.player.rotation = (self.player.rotation + 2) % 4;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{announcement, ClearKind};
+
+    // a seeded game whose stage ends in `rows`, see `State::with_board`
+    fn board(rows: &[&str]) -> State {
+        let settings = Settings {
+            seed: Some(1),
+            ..Settings::default()
+        };
+        State::with_board(settings, rows).unwrap()
+    }
+
+    fn offsets(piece_type: PieceType) -> Vec<(usize, usize)> {
+        PieceSet::default().offsets(&piece_type, 0).to_vec()
+    }
+
+    fn game(rows: &[&str]) -> Game {
+        Game { state: board(rows) }
+    }
+
+    // swaps the falling piece for `piece_type` in its spawn orientation at x, y
+    fn place(game: &mut Game, piece_type: PieceType, x: isize, y: isize) {
+        let (piece_type, piece_shape) = game.state.pieces.spawn(piece_type);
+        let player = &mut game.state.player;
+        player.piece_type = piece_type;
+        player.piece_shape = piece_shape;
+        player.rotation = 0;
+        player.position = Position { x, y };
+    }
+
+    fn rows(state: &State) -> Vec<String> {
+        state
+            .stage
+            .data
+            .chunks(state.stage.n_cols)
+            .map(|row| row.iter().map(|cell| cell.as_ref()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn pieces_hug_each_wall() {
+        let state = board(&[]);
+        // the T fills the first and last column of its grid
+        let t = offsets(PieceType::T);
+        assert!(state.is_player_position_valid(0, 5, &t));
+        assert!(!state.is_player_position_valid(-1, 5, &t));
+        assert!(state.is_player_position_valid(7, 5, &t));
+        assert!(!state.is_player_position_valid(8, 5, &t));
+        // the I stands in the second column of its grid
+        let i = offsets(PieceType::I);
+        assert!(state.is_player_position_valid(-1, 5, &i));
+        assert!(!state.is_player_position_valid(-2, 5, &i));
+        assert!(state.is_player_position_valid(8, 5, &i));
+        assert!(!state.is_player_position_valid(9, 5, &i));
+    }
+
+    #[test]
+    fn piece_rests_on_a_ledge() {
+        let state = board(&["XXX.......", "XXX......."]);
+        let o = offsets(PieceType::O);
+        assert!(state.is_position_empty(0, 17, &o) && state.is_player_position_valid(0, 17, &o));
+        assert!(!state.is_position_empty(0, 18, &o));
+        // beside the ledge it falls to the floor
+        assert!(state.is_position_empty(3, 19, &o) && state.is_player_position_valid(3, 19, &o));
+        assert!(!state.is_player_position_valid(3, 20, &o));
+    }
+
+    #[test]
+    fn completed_bottom_row_is_found() {
+        let state = board(&["X.........", "XXXXXXXXXX"]);
+        assert_eq!(state.get_completed_rows(), vec![20]);
+    }
+
+    #[test]
+    fn completed_rows_are_found_on_a_wider_stage() {
+        let settings = Settings {
+            n_cols: 12,
+            seed: Some(1),
+            ..Settings::default()
+        };
+        let state = State::with_board(
+            settings,
+            &["XXXXXXXXXXXX", "XXXXXXXXXX..", "XXXXXXXXXXXX"],
+        )
+        .unwrap();
+        assert_eq!(state.get_completed_rows(), vec![18, 20]);
+    }
+
+    #[test]
+    fn completed_row_is_found_on_a_forty_column_stage() {
+        let settings = Settings {
+            n_cols: 40,
+            seed: Some(1),
+            ..Settings::default()
+        };
+        let full = "X".repeat(40);
+        let gap = format!("{}.", "X".repeat(39));
+        let state = State::with_board(settings, &[&gap, &full]).unwrap();
+        assert_eq!(state.get_completed_rows(), vec![20]);
+    }
+
+    #[test]
+    fn overhang_blocks_the_piece_below_it() {
+        let state = board(&[".X........", ".........."]);
+        let o = offsets(PieceType::O);
+        assert!(state.is_position_empty(0, 17, &o));
+        // the cells under the overhang are empty, but the O can't get past it
+        assert!(!state.is_position_empty(0, 18, &o));
+        assert!(state.is_position_empty(2, 19, &o));
+    }
+
+    #[test]
+    fn board_rows_must_fit_the_stage() {
+        let settings = Settings::default();
+        assert!(State::with_board(settings.clone(), &["XXXX"]).is_err());
+        assert!(State::with_board(settings, &["XXXXXXXXX?"]).is_err());
+    }
+
+    #[test]
+    fn moves_into_the_stack_are_blocked() {
+        let mut game = game(&["XX........", "XX........"]);
+        place(&mut game, PieceType::O, 2, 19);
+        assert_eq!(game.step(Controls::Left), StepOutcome::Blocked);
+        // still on the floor, so the lock delay starts over
+        assert_eq!(game.step(Controls::Right), StepOutcome::LockReset);
+        assert_eq!(game.state.player.position.x, 3);
+    }
+
+    #[test]
+    fn hard_drop_lands_on_the_floor_and_clears_a_tetris() {
+        let mut game = game(&[".XXXXXXXXX"; 4]);
+        // the I stands in the second column of its grid
+        place(&mut game, PieceType::I, -1, 0);
+        assert_eq!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 4 });
+        assert!(game.state.is_board_empty());
+        assert_eq!(game.state.game_status.rows_cleared, 4);
+    }
+
+    #[test]
+    fn clearing_a_row_drops_the_rows_above() {
+        let mut game = game(&["TT........", "TTTTTTTT.."]);
+        place(&mut game, PieceType::O, 8, 0);
+        assert_eq!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 1 });
+        let rows = rows(&game.state);
+        assert_eq!(rows[20], "TTEEEEEEOO");
+        assert!(rows[..20].iter().all(|row| row == "EEEEEEEEEE"));
+    }
+
+    #[test]
+    fn rotation_is_blocked_by_the_stack() {
+        let mut game = game(&["XXXXXXXXX.", "XXXXXXXXX.", "XXXXXXXXX.", "XXXXXXXXX."]);
+        game.state.rotation_system = RotationSystem::Simple;
+        // a vertical I down the well can't turn flat
+        place(&mut game, PieceType::I, 8, 17);
+        assert_eq!(game.step(Controls::Rotate), StepOutcome::Blocked);
+        assert_eq!(game.state.player.rotation, 0);
+    }
+
+    #[test]
+    fn i_against_the_left_wall_kicks_out_to_turn() {
+        let mut game = game(&[]);
+        // standing in column 0, flat it would stick out past the wall
+        place(&mut game, PieceType::I, -1, 10);
+        assert_eq!(game.step(Controls::Rotate), StepOutcome::Moved);
+        assert_eq!(game.state.player.rotation, 1);
+        assert!(game.state.player.position.x > -1);
+        let cells = player_cells(&game);
+        assert!(cells.iter().all(|(x, y)| *x >= 0 && *y == cells[0].1));
+        assert_eq!(cells[0].0, 0);
+    }
+
+    #[test]
+    fn i_against_the_right_wall_kicks_out_to_turn() {
+        let mut game = game(&[]);
+        place(&mut game, PieceType::I, 8, 10);
+        assert_eq!(game.step(Controls::Rotate), StepOutcome::Moved);
+        assert_eq!(game.state.player.rotation, 1);
+        assert!(game.state.player.position.x < 8);
+        let cells = player_cells(&game);
+        assert!(cells.iter().all(|(x, y)| *x <= 9 && *y == cells[0].1));
+        assert_eq!(cells[3].0, 9);
+    }
+
+    #[test]
+    fn only_srs_turns_an_i_flush_against_the_wall() {
+        let mut game = game(&[]);
+        game.state.rotation_system = RotationSystem::Simple;
+        place(&mut game, PieceType::I, -1, 10);
+        // the simple system only tries the spot and the row above it
+        assert_eq!(game.step(Controls::Rotate), StepOutcome::Blocked);
+        assert_eq!(game.state.player.rotation, 0);
+        assert_eq!((game.state.player.position.x, game.state.player.position.y), (-1, 10));
+
+        game.state.rotation_system = RotationSystem::Srs;
+        assert_eq!(game.step(Controls::Rotate), StepOutcome::Moved);
+        assert_eq!(game.state.player.rotation, 1);
+    }
+
+    #[test]
+    fn turning_an_o_leaves_it_where_it_is() {
+        let mut game = game(&[]);
+        place(&mut game, PieceType::O, 8, 19);
+        let data = game.state.player.piece_shape.data.clone();
+        // on the floor against the wall, where a kick would have to shift it
+        for control in [Controls::Rotate, Controls::RotateCCW, Controls::Rotate180] {
+            assert_eq!(game.step(control), StepOutcome::Moved);
+            let Position { x, y } = game.state.player.position;
+            assert_eq!((x, y), (8, 19));
+            assert_eq!(game.state.player.piece_shape.data, data);
+        }
+        // and none of those turns spent a lock delay restart
+        assert_eq!(game.state.lock_resets, 0);
+    }
+
+    fn seeded(seed: u64) -> Game {
+        Game::new(Settings {
+            seed: Some(seed),
+            ..Settings::default()
+        })
+    }
+
+    #[test]
+    fn ticks_carry_a_piece_down_until_it_locks() {
+        let mut game = seeded(3);
+        let mut ticks = 0;
+        while game.tick() == StepOutcome::Moved {
+            ticks += 1;
+        }
+        assert!(ticks > 0);
+        assert_eq!(game.snapshot().player.position.y, game.ghost_y());
+        assert_eq!(game.lock(), StepOutcome::Locked { rows_cleared: 0 });
+        assert!(!game.snapshot().is_board_empty());
+    }
+
+    #[test]
+    fn grounded_piece_out_of_lock_resets_locks_at_the_next_tick() {
+        let mut game = game(&[]);
+        game.state.lock_reset_cap = 3;
+        place(&mut game, PieceType::T, 4, 10);
+        while game.tick() == StepOutcome::Moved {}
+        assert!(!game.is_force_locking());
+        // each slide along the floor restarts the lock delay, up to the cap
+        for side in [Controls::Left, Controls::Right, Controls::Left] {
+            assert_eq!(game.step(side), StepOutcome::LockReset);
+        }
+        assert_eq!(game.step(Controls::Right), StepOutcome::Moved);
+        assert!(game.is_force_locking());
+        assert_eq!(game.tick(), StepOutcome::Locked { rows_cleared: 0 });
+        // the next piece starts with all of its resets
+        assert_eq!(game.state.lock_resets, 0);
+    }
+
+    #[test]
+    fn lines_to_the_next_level_count_down_from_ten() {
+        // (lines cleared, level started on, level reached, lines still to go)
+        let cases = [(9, 1, 1, 1), (10, 1, 2, 10), (11, 1, 2, 9), (11, 5, 5, 39)];
+        for (lines, start_level, level, to_go) in cases {
+            let mut game = self::game(&["XXXXXXXXX."]);
+            game.state.game_status.start_level = start_level;
+            game.state.game_status.rows_cleared = lines - 1;
+            place(&mut game, PieceType::I, 8, 17);
+            assert_eq!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 1 });
+            assert_eq!(game.state.game_status.level, level);
+            assert_eq!(game.state.game_status.lines_to_next_level(), to_go);
+        }
+    }
+
+    #[test]
+    fn hard_drops_in_one_column_top_out() {
+        let mut game = seeded(5);
+        let mut pieces = 0;
+        while !game.is_over() && pieces < 100 {
+            game.step(Controls::Bottom);
+            pieces += 1;
+        }
+        assert!(game.is_over());
+        assert!(game.snapshot().game_status.score > 0);
+        // nothing moves once the game is over
+        assert_eq!(game.step(Controls::Left), StepOutcome::Blocked);
+        assert_eq!(game.tick(), StepOutcome::Blocked);
+    }
+
+    #[test]
+    fn same_seed_and_inputs_play_the_same_game() {
+        let inputs = [
+            Controls::Left,
+            Controls::Rotate,
+            Controls::Bottom,
+            Controls::Right,
+            Controls::Right,
+            Controls::Bottom,
+            Controls::Hold,
+            Controls::RotateCCW,
+            Controls::Bottom,
+        ];
+        let mut games = [seeded(11), seeded(11)];
+        for game in games.iter_mut() {
+            for input in inputs.iter() {
+                game.step(input.clone());
+                game.tick();
+            }
+        }
+        assert_eq!(rows(games[0].snapshot()), rows(games[1].snapshot()));
+        assert_eq!(games[0].snapshot().next_queue, games[1].snapshot().next_queue);
+    }
+
+    #[test]
+    fn cheese_picked_mid_game_waits_for_the_next_one() {
+        let mut game = seeded(5);
+        game.state.next_mode = GameMode::Cheese;
+        for _ in 0..CHEESE_INTERVAL * 3 {
+            game.state.replay.record(0.0, ReplayEvent::Second);
+            game.play(ReplayEvent::Second);
+        }
+        assert!(game.state.stage.data.iter().all(|cell| *cell == PieceType::E));
+        let replayed = Game::from_replay(game.state.replay.clone());
+        assert_eq!(rows(replayed.snapshot()), rows(game.snapshot()));
+    }
+
+    #[test]
+    fn practice_rules_follow_the_mode_a_game_started_in() {
+        let mut game = seeded(6);
+        game.state.next_mode = GameMode::Practice;
+        game.step(Controls::Bottom);
+        assert!(game.state.history.nodes.is_empty());
+        assert_eq!(game.step(Controls::Undo), StepOutcome::Blocked);
+
+        game.state.initialize_game();
+        let before = rows(game.snapshot());
+        game.step(Controls::Bottom);
+        assert_eq!(game.state.history.nodes.len(), 1);
+        assert_eq!(game.step(Controls::Undo), StepOutcome::Moved);
+        assert_eq!(rows(game.snapshot()), before);
+
+        // a clear taken back leaves the status as it was, still in play
+        let mut cleared = self::game(&["XXXXXXXXX."]);
+        cleared.state.mode = GameMode::Practice;
+        let before = cleared.state.game_status.clone();
+        place(&mut cleared, PieceType::I, 8, 17);
+        assert_eq!(cleared.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 1 });
+        assert_eq!(cleared.state.game_status.rows_cleared, 1);
+        assert_eq!(cleared.step(Controls::Undo), StepOutcome::Moved);
+        let status = &cleared.state.game_status;
+        assert_eq!((status.rows_cleared, status.score, status.level), (0, 0, before.level));
+        assert_eq!((status.game_over, status.game_won), (false, false));
+        assert_eq!(rows(cleared.snapshot())[20], "GGGGGGGGGE");
+    }
+
+    #[test]
+    fn ghost_follows_the_piece_swapped_in_by_a_hold() {
+        // a tower under the left wall, nothing under the spawn
+        let mut game = game(&["X........."; 12]);
+        place(&mut game, PieceType::T, 0, 3);
+        let held_ghost = game.ghost_y();
+        assert_eq!(game.step(Controls::Hold), StepOutcome::Moved);
+        assert_eq!(game.state.held_piece, Some(PieceType::T));
+        // no move since the hold, the ghost is already the new piece's
+        let ghost = game.ghost_y();
+        assert!(ghost > held_ghost);
+        while game.tick() == StepOutcome::Moved {}
+        assert_eq!(game.state.player.position.y, ghost);
+    }
+
+    #[test]
+    fn blind_mode_reads_out_the_piece_and_its_leftmost_column() {
+        let mut game = game(&[]);
+        // the I stands in the second column of its grid
+        place(&mut game, PieceType::I, -1, 10);
+        assert_eq!(announcement("Moved", &game.state.player), "Moved. I piece, column 1");
+        place(&mut game, PieceType::T, 7, 10);
+        assert_eq!(announcement("Rotated", &game.state.player), "Rotated. T piece, column 8");
+
+        // and only blind mode keeps the announcement up to date
+        assert_eq!(game.step(Controls::Left), StepOutcome::Moved);
+        assert_eq!(game.state.announcement, "");
+        game.state.blind_mode = true;
+        assert_eq!(game.step(Controls::Left), StepOutcome::Moved);
+        assert_eq!(game.state.announcement, "Moved left. T piece, column 6");
+        assert_eq!(game.step(Controls::Hold), StepOutcome::Moved);
+        assert!(game.state.announcement.starts_with("Held. "));
+    }
+
+    // rotates a T once, holds it and brings it back after the next piece locks
+    fn hold_rotated_t(hold_keeps_rotation: bool) -> Player {
+        let mut game = seeded(9);
+        game.state.hold_keeps_rotation = hold_keeps_rotation;
+        place(&mut game, PieceType::T, 3, 2);
+        assert_eq!(game.step(Controls::Rotate), StepOutcome::Moved);
+        game.step(Controls::Hold);
+        game.step(Controls::Bottom);
+        assert_eq!(game.step(Controls::Hold), StepOutcome::Moved);
+        game.state.player.clone()
+    }
+
+    #[test]
+    fn held_piece_comes_back_in_spawn_orientation() {
+        let player = hold_rotated_t(false);
+        assert_eq!(player.piece_type, PieceType::T);
+        assert_eq!(player.rotation, 0);
+        assert_eq!(player.piece_shape.occupied(), offsets(PieceType::T));
+    }
+
+    #[test]
+    fn held_piece_can_keep_its_rotation() {
+        let player = hold_rotated_t(true);
+        assert_eq!(player.rotation, 1);
+        let turned = PieceSet::default().offsets(&PieceType::T, 1).to_vec();
+        assert_eq!(player.piece_shape.occupied(), turned);
+    }
+
+    #[test]
+    fn undo_branch_and_redo_walk_the_tree() {
+        let mut game = seeded(10);
+        game.state.next_mode = GameMode::Practice;
+        game.state.initialize_game();
+        let empty = rows(game.snapshot());
+        game.step(Controls::Bottom);
+        let first_try = rows(game.snapshot());
+
+        assert_eq!(game.step(Controls::Undo), StepOutcome::Moved);
+        assert_eq!(rows(game.snapshot()), empty);
+        game.step(Controls::Left);
+        game.step(Controls::Left);
+        game.step(Controls::Bottom);
+        let second_try = rows(game.snapshot());
+        assert_ne!(second_try, first_try);
+
+        // back to the second placement, then to the piece before either
+        game.step(Controls::Undo);
+        game.step(Controls::Undo);
+        assert_eq!(rows(game.snapshot()), empty);
+        assert_eq!(game.state.history.branches(), Some((1, 2)));
+        assert_eq!(game.step(Controls::Redo), StepOutcome::Moved);
+        assert_eq!(rows(game.snapshot()), first_try);
+        assert_eq!(game.step(Controls::Redo), StepOutcome::Blocked);
+
+        game.step(Controls::Undo);
+        assert_eq!(game.step(Controls::Branch), StepOutcome::Moved);
+        assert_eq!(game.state.history.branches(), Some((2, 2)));
+        game.step(Controls::Redo);
+        game.step(Controls::Redo);
+        assert_eq!(rows(game.snapshot()), second_try);
+    }
+
+    #[test]
+    fn only_soft_drops_score_points() {
+        let mut game = seeded(15);
+        for _ in 0..5 {
+            assert_eq!(game.tick(), StepOutcome::Moved);
+        }
+        assert_eq!(game.state.game_status.score, 0);
+        for _ in 0..5 {
+            assert_eq!(game.step(Controls::SoftDrop), StepOutcome::Moved);
+        }
+        assert_eq!(game.state.game_status.score, 5 * SOFT_DROP_POINTS);
+    }
+
+    // (x, y) stage cells the falling piece covers
+    fn player_cells(game: &Game) -> Vec<(isize, isize)> {
+        let Position { x, y } = game.state.player.position;
+        let mut cells: Vec<(isize, isize)> = game
+            .state
+            .player
+            .piece_shape
+            .occupied()
+            .into_iter()
+            .map(|(row, col)| (x + col as isize, y + row as isize))
+            .collect();
+        cells.sort();
+        cells
+    }
+
+    #[test]
+    fn four_i_rotations_come_back_to_the_spawn_cells() {
+        let mut game = game(&[]);
+        place(&mut game, PieceType::I, 3, 5);
+        let spawn_shape = game.state.player.piece_shape.data.clone();
+        // the I turns within its 4x4 box: column 1, row 1, column 2, row 2
+        let column = |col: isize| (5..9).map(|y| (3 + col, y)).collect::<Vec<_>>();
+        let row = |row: isize| (3..7).map(|x| (x, 5 + row)).collect::<Vec<_>>();
+        let states = [column(1), row(1), column(2), row(2), column(1)];
+        assert_eq!(player_cells(&game), states[0]);
+        for cells in states[1..].iter() {
+            assert_eq!(game.step(Controls::Rotate), StepOutcome::Moved);
+            assert_eq!(&player_cells(&game), cells);
+        }
+        assert_eq!(game.state.player.piece_shape.data, spawn_shape);
+    }
+
+    #[test]
+    fn preview_shows_the_pieces_that_spawn_next() {
+        let mut game = seeded(16);
+        for _ in 0..100 {
+            let preview = game.state.next_queue.clone();
+            game.step(Controls::Bottom);
+            assert_eq!(game.state.player.piece_type, preview[0]);
+            assert_eq!(game.state.next_queue[..preview.len() - 1], preview[1..]);
+            // an empty stage every time, so the drops never top out
+            game.state.stage.data.iter_mut().for_each(|cell| *cell = PieceType::E);
+        }
+    }
+
+    #[test]
+    fn t_turns_upside_down_and_kicks_off_the_floor() {
+        let mut game = game(&[]);
+        place(&mut game, PieceType::T, 3, 5);
+        assert_eq!(game.step(Controls::Rotate180), StepOutcome::Moved);
+        let upside_down: Vec<PieceType> = "EEETTTETE"
+            .chars()
+            .map(|cell| if cell == 'T' { PieceType::T } else { PieceType::E })
+            .collect();
+        assert_eq!(game.state.player.piece_shape.data, upside_down);
+        assert_eq!((game.state.player.position.x, game.state.player.position.y), (3, 5));
+
+        // flat on the floor the stem has no room below, the flip lifts it a row
+        place(&mut game, PieceType::T, 3, 19);
+        assert_eq!(game.step(Controls::Rotate180), StepOutcome::LockReset);
+        assert_eq!((game.state.player.position.x, game.state.player.position.y), (3, 18));
+        assert_eq!(game.state.player.last_kick, Some(1));
+    }
+
+    #[test]
+    fn t_flipped_once_is_the_spawn_shape_reversed() {
+        let mut game = game(&[]);
+        place(&mut game, PieceType::T, 3, 5);
+        let mut reversed = game.state.player.piece_shape.data.clone();
+        reversed.reverse();
+        game.state.rotate_player_piece_180();
+        assert_eq!(game.state.player.piece_shape.data, reversed);
+        assert_eq!(game.state.player.rotation, 2);
+        game.state.rotate_player_piece_180();
+        assert_eq!(game.state.player.rotation, 0);
+    }
+
+    #[test]
+    fn removing_rows_keeps_the_rest_in_order() {
+        let mut state = board(&["T.........", "XXXXXXXXXX", "..O.......", "XXXXXXXXXX"]);
+        state.remove_rows(vec![18, 20]);
+        assert_eq!(rows(&state), rows(&board(&["T.........", "..O......."])));
+
+        let mut state = board(&[
+            "..L.......",
+            "XXXXXXXXX.",
+            "XXXXXXXXXX",
+            "XXXXXXXXXX",
+            "XXXXXXXXXX",
+            "XXXXXXXXXX",
+        ]);
+        state.remove_rows(vec![17, 18, 19, 20]);
+        assert_eq!(rows(&state), rows(&board(&["..L.......", "XXXXXXXXX."])));
+
+        let mut state = board(&["S.........", "XXXXXXXXXX", "Z........."]);
+        state.remove_rows(vec![19]);
+        assert_eq!(rows(&state), rows(&board(&["S.........", "Z........."])));
+    }
+
+    #[test]
+    fn piece_locks_flush_in_the_bottom_right_corner() {
+        let mut game = game(&[]);
+        place(&mut game, PieceType::O, 8, 5);
+        assert_eq!(game.step(Controls::Right), StepOutcome::Blocked);
+        assert!(matches!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 0 }));
+        assert_eq!(rows(game.snapshot()), rows(&board(&["........OO", "........OO"])));
+    }
+
+    #[test]
+    fn rows_cleared_apart_drop_the_rows_between() {
+        let mut game = game(&["XXXXXXXXX.", "XXXX.XXXX.", "XXXXXXXXX.", "XXX.XXXXX."]);
+        place(&mut game, PieceType::I, 8, 0);
+        assert!(matches!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 2 }));
+        assert_eq!(rows(game.snapshot()), rows(&board(&["XXXX.XXXXI", "XXX.XXXXXI"])));
+    }
+
+    #[test]
+    fn game_in_progress_round_trips_through_json() {
+        let mut game = seeded(17);
+        for control in [Controls::Left, Controls::Bottom, Controls::Rotate, Controls::Bottom] {
+            game.step(control);
+        }
+        game.step(Controls::Hold);
+        let restored = State::from_json(&game.state.to_json()).unwrap();
+        assert_eq!(rows(&restored), rows(&game.state));
+        assert_eq!(restored.player.piece_type, game.state.player.piece_type);
+        assert_eq!(restored.held_piece, game.state.held_piece);
+        assert_eq!(restored.game_status.score, game.state.game_status.score);
+    }
+
+    #[test]
+    fn back_to_back_tetrises_score_half_again_without_the_perfect_clear_bonus() {
+        // the I stands in the second column of its grid, resting on the floor
+        // so the drop scores nothing
+        let mut rows = vec![".........X"];
+        rows.extend(&[".XXXXXXXXX"; 8]);
+        let mut game = game(&rows);
+        place(&mut game, PieceType::I, -1, 17);
+        assert_eq!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 4 });
+        assert_eq!(game.state.game_status.score, 1200);
+        assert!(game.state.game_status.back_to_back);
+        place(&mut game, PieceType::I, -1, 17);
+        assert_eq!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 4 });
+        // 1200 and then 1800 for the back-to-back Tetris, with 50 for the combo
+        assert_eq!(game.state.game_status.score, 3050);
+
+        let mut cleared = self::game(&[".XXXXXXXXX"; 8]);
+        place(&mut cleared, PieceType::I, -1, 17);
+        cleared.step(Controls::Bottom);
+        place(&mut cleared, PieceType::I, -1, 17);
+        cleared.step(Controls::Bottom);
+        assert!(cleared.state.perfect_clear);
+        assert_eq!(cleared.state.game_status.score, 1200 + 1800 + 6000 + 50);
+    }
+
+    #[test]
+    fn clearing_the_whole_board_with_one_piece_is_a_perfect_clear() {
+        let boards: [(&[&str], PieceType, isize, isize, usize); 4] = [
+            (&["XXXXXX...."], PieceType::I, 6, 19, 40 + 3000),
+            (&["XXXXXXXX.."; 2], PieceType::O, 8, 19, 100 + 4000),
+            (&["XXXXXXXX.X", "XXXXXXXX.X", "XXXXXXXX.."], PieceType::L, 7, 18, 300 + 5000),
+            (&["XXXXXXXXX."; 4], PieceType::I, 8, 17, 1200 + 6000),
+        ];
+        for (rows_cleared, (rows, piece_type, x, y, score)) in (1..).zip(boards.iter()) {
+            let mut game = game(rows);
+            place(&mut game, piece_type.clone(), *x, *y);
+            if rows_cleared == 1 {
+                // lying flat along the second row of its grid
+                game.state.rotate_player_piece();
+            }
+            assert_eq!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared });
+            assert!(game.state.is_board_empty());
+            assert!(game.state.perfect_clear);
+            assert_eq!(game.state.game_status.score, *score);
+        }
+    }
+
+    #[test]
+    fn four_line_clear_shows_tetris_until_the_next_clear() {
+        let mut game = game(&["XXXXXXXXX."; 4]);
+        place(&mut game, PieceType::I, 8, 17);
+        assert_eq!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 4 });
+        assert_eq!(game.state.last_clear, Some(ClearKind::Tetris));
+        assert_eq!(game.state.last_clear.as_ref().unwrap().as_ref(), "Tetris");
+        assert!(game.state.game_status.back_to_back);
+        // a piece that clears nothing leaves it up
+        place(&mut game, PieceType::O, 0, 19);
+        assert_eq!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 0 });
+        assert_eq!(game.state.last_clear, Some(ClearKind::Tetris));
+    }
+
+    #[test]
+    fn drop_trail_runs_from_where_the_piece_was_to_where_it_landed() {
+        let mut game = game(&[]);
+        game.state.trail_enabled = true;
+        place(&mut game, PieceType::O, 4, 5);
+        assert_eq!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 0 });
+        let trail = game.state.trail.as_ref().unwrap();
+        // the O filled rows 5 and 6 and landed on rows 19 and 20
+        assert_eq!((trail.top, trail.bottom), (5, 19));
+        assert_eq!(trail.cols, vec![4, 5]);
+        for row in 5..19 {
+            assert!(trail.opacity(row, 4).is_some() && trail.opacity(row, 5).is_some());
+        }
+        assert_eq!(trail.opacity(4, 4), None);
+        assert_eq!(trail.opacity(19, 4), None);
+        assert_eq!(trail.opacity(10, 6), None);
+        // brightest just above the landing spot
+        assert!(trail.opacity(18, 4) > trail.opacity(5, 4));
+
+        game.state.trail_enabled = false;
+        place(&mut game, PieceType::O, 0, 5);
+        game.step(Controls::Bottom);
+        assert!(game.state.trail.is_none());
+    }
+
+    #[test]
+    fn consecutive_clears_build_a_combo_until_a_piece_clears_nothing() {
+        let mut game = game(&["XXXXXXXX.."; 6]);
+        // each double scores 100, plus 50 for every step of the combo
+        for (combo, score) in [(0, 100), (1, 250)] {
+            place(&mut game, PieceType::O, 8, 19);
+            assert_eq!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 2 });
+            assert_eq!(game.state.game_status.combo, combo);
+            assert_eq!(game.state.game_status.score, score);
+        }
+        place(&mut game, PieceType::O, 0, 17);
+        assert_eq!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 0 });
+        assert_eq!(game.state.game_status.combo, -1);
+        assert_eq!(game.state.game_status.score, 250);
+        place(&mut game, PieceType::O, 8, 19);
+        assert_eq!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 2 });
+        assert_eq!(game.state.game_status.combo, 0);
+        assert_eq!(game.state.game_status.score, 350);
+    }
+
+    // a T pointing right beside the slot in `rows`, turned down into it
+    fn spin_into_slot(rows: &[&str]) -> Game {
+        let mut game = game(rows);
+        place(&mut game, PieceType::T, 3, 18);
+        game.state.rotate_player_piece();
+        assert_eq!(game.step(Controls::Rotate), StepOutcome::LockReset);
+        assert_eq!(game.state.player.rotation, 2);
+        game
+    }
+
+    #[test]
+    fn t_turned_down_into_a_slot_is_a_t_spin() {
+        let mut game = spin_into_slot(&["XXXX......", "XXX...XXXX", "XXXX.XXXXX"]);
+        assert_eq!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 2 });
+        assert_eq!(game.state.last_clear, Some(ClearKind::TSpinDouble));
+        assert_eq!(game.state.game_status.score, 1200);
+
+        let mut game = spin_into_slot(&["XXXX......", "XXX...XXXX", "XXXX.XXX.X"]);
+        assert_eq!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 1 });
+        assert_eq!(game.state.last_clear, Some(ClearKind::TSpinSingle));
+        assert_eq!(game.state.game_status.score, 800);
+    }
+
+    #[test]
+    fn t_pointing_away_from_a_blocked_corner_is_a_mini() {
+        let mut game = game(&["...X......", "XXX...XXXX", "XXXX.XXXXX"]);
+        // pointing left, then turned up with only the top left corner blocked
+        // of the two it points at
+        place(&mut game, PieceType::T, 3, 18);
+        for _ in 0..3 {
+            game.state.rotate_player_piece();
+        }
+        assert_eq!(game.step(Controls::Rotate), StepOutcome::LockReset);
+        assert_eq!(game.state.player.last_kick, Some(0));
+        assert_eq!(game.t_spin(), Some(TSpin::Mini));
+
+        // the fifth kick only lifts a T deep into a slot in the SRS quarter
+        // turn tables, the same index in the 180 table is a sideways step
+        game.state.player.last_kick = Some(LAST_KICK);
+        game.state.player.quarter_kick = false;
+        assert_eq!(game.t_spin(), Some(TSpin::Mini));
+        game.state.player.quarter_kick = true;
+        assert_eq!(game.t_spin(), Some(TSpin::Full));
+
+        game.state.player.last_kick = Some(0);
+        assert_eq!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 1 });
+        assert_eq!(game.state.last_clear, Some(ClearKind::MiniTSpinSingle));
+        assert_eq!(game.state.game_status.score, 200);
+    }
+
+    #[test]
+    fn finesse_minimum_taps_or_holds_das_to_the_wall() {
+        // piece, quarter turns, x and the fewest presses that get it there
+        // from the spawn at x = 4
+        let placements = [
+            (PieceType::T, 0, 4, 0),
+            (PieceType::T, 0, 3, 1),
+            (PieceType::T, 0, 2, 2),
+            // a DAS to the left wall and a tap back beats three taps
+            (PieceType::T, 0, 1, 2),
+            (PieceType::T, 0, 0, 1),
+            (PieceType::T, 0, 6, 2),
+            (PieceType::T, 0, 7, 1),
+            (PieceType::T, 1, 4, 1),
+            (PieceType::T, 2, 0, 2),
+            // the I stands in the second column of its grid
+            (PieceType::I, 0, -1, 1),
+            (PieceType::O, 0, 8, 1),
+        ];
+        let mut game = game(&[]);
+        for (piece_type, turns, x, presses) in placements.iter().cloned() {
+            place(&mut game, piece_type.clone(), x, 5);
+            for _ in 0..turns {
+                game.state.rotate_player_piece();
+            }
+            assert_eq!(game.state.finesse_minimum(), presses, "{:?} at {}", piece_type, x);
+        }
+
+        // a held key is one press however far DAS carries the piece, four
+        // taps to the wall are a fault
+        for (inputs, faults) in [(1, 0), (4, 1)] {
+            place(&mut game, PieceType::T, 0, 5);
+            game.state.player.inputs = inputs;
+            game.step(Controls::Bottom);
+            assert_eq!(game.state.game_status.finesse_faults, faults);
+        }
+    }
+
+    #[test]
+    fn tetris_on_one_board_queues_garbage_on_the_other() {
+        let mut versus = Versus::new(Settings {
+            seed: Some(1),
+            ..Settings::default()
+        });
+        let well = ["XXXXXXXXX.", "XXXXXXXXX.", "XXXXXXXXX.", "XXXXXXXXX."];
+        versus.games[0] = self::game(&well);
+        place(&mut versus.games[0], PieceType::I, 8, 0);
+        let tetris = versus.play(0, ReplayEvent::Step(Controls::Bottom));
+        assert_eq!(tetris, StepOutcome::Locked { rows_cleared: 4 });
+        assert_eq!(versus.pending_garbage, [0, 4]);
+        assert!(!versus.games[1].state.has_garbage());
+
+        // it rises once a piece of player two locks without clearing a row
+        versus.play(1, ReplayEvent::Step(Controls::Bottom));
+        assert_eq!(versus.pending_garbage, [0, 0]);
+        let board_two = rows(&versus.games[1].state);
+        let bottom = &board_two[board_two.len() - 4..];
+        assert!(bottom.iter().all(|row| row.matches('G').count() == 9));
+        assert_eq!(versus.winner(), None);
+    }
+
+    #[test]
+    fn versus_gravity_locks_a_landed_piece_at_the_next_row() {
+        let mut versus = Versus::new(Settings {
+            seed: Some(3),
+            ..Settings::default()
+        });
+        let row_ms = versus.games[0].state.gravity();
+        assert!(versus.fall(row_ms * 30.0));
+        for game in &versus.games {
+            let board = rows(&game.state);
+            assert!(board.last().unwrap().contains(|cell| cell != 'E'));
+            assert_eq!(game.state.game_status.rows_cleared, 0);
+        }
+        // both boards were dealt the same pieces
+        assert_eq!(rows(&versus.games[0].state), rows(&versus.games[1].state));
+        assert!(!versus.fall(row_ms / 2.0));
+    }
+}
@@ -2,22 +2,41 @@
 
 #[macro_use]
 extern crate lazy_static;
+#[macro_use]
 extern crate stdweb;
 
-use log::info;
+mod engine;
+
+pub use engine::{
+    verify_replay, Game, Playback, Replay, ReplayEvent, Settings, StepOutcome, Versus,
+};
+
+use engine::{UndoTree, LOCK_RESET_CAP};
+
+use log::{error, info};
 use rand::prelude::*;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::time::Duration;
 use stdweb::traits::*;
-use stdweb::web::document;
+use stdweb::unstable::TryInto;
+use stdweb::web::html_element::CanvasElement;
+use stdweb::web::{
+    document, window, CanvasRenderingContext2d, Date, Element, EventListenerHandle, EventTarget,
+    Touch,
+};
+use stdweb::Value;
 use strum::IntoEnumIterator;
-use strum_macros::{AsRefStr, EnumIter, ToString};
-use yew::events::IKeyboardEvent;
+use strum_macros::{AsRefStr, EnumIter};
+use yew::events::{IKeyboardEvent, MouseDownEvent, TouchEnd, TouchMove, TouchStart};
 use yew::format::Json;
 use yew::services::storage::{Area, StorageService};
-use yew::services::{IntervalService, Task};
-use yew::{html, Callback, Component, ComponentLink, Href, Html, KeyDownEvent, ShouldRender};
+use yew::services::render::RenderTask;
+use yew::services::{IntervalService, RenderService, Task, TimeoutService};
+use yew::{
+    html, Callback, Component, ComponentLink, Html, InputData, KeyDownEvent, KeyUpEvent,
+    NodeRef, ShouldRender,
+};
 
 lazy_static! {
     #[derive(Debug)]
@@ -25,130 +44,262 @@ lazy_static! {
         let mut map = HashMap::new();
         map.insert(
             PieceType::E.as_ref(),
-            Piece {
-                shape: Vec2D {
-                    n_rows: 1,
-                    n_cols: 1,
-                    data: vec![PieceType::E]
-                }
-            },
+            Piece::new(Vec2D {
+                n_rows: 1,
+                n_cols: 1,
+                data: vec![PieceType::E]
+            })
+            .unwrap(),
         );
         map.insert(
             PieceType::I.as_ref(),
-            Piece {
-                shape: Vec2D {
-                    n_rows: 4,
-                    n_cols: 4,
-                    data: vec![
-                        PieceType::E, PieceType::I, PieceType::E, PieceType::E,
-                        PieceType::E, PieceType::I, PieceType::E, PieceType::E,
-                        PieceType::E, PieceType::I, PieceType::E, PieceType::E,
-                        PieceType::E, PieceType::I, PieceType::E, PieceType::E,
-                    ]
-                }
-            },
+            Piece::new(Vec2D {
+                n_rows: 4,
+                n_cols: 4,
+                data: vec![
+                    PieceType::E, PieceType::I, PieceType::E, PieceType::E,
+                    PieceType::E, PieceType::I, PieceType::E, PieceType::E,
+                    PieceType::E, PieceType::I, PieceType::E, PieceType::E,
+                    PieceType::E, PieceType::I, PieceType::E, PieceType::E,
+                ]
+            })
+            .unwrap(),
         );
         map.insert(
             PieceType::J.as_ref(),
-            Piece {
-                shape: Vec2D {
-                    n_rows: 3,
-                    n_cols: 3,
-                    data: vec![
-                      PieceType::E, PieceType::J, PieceType::E,
-                      PieceType::E, PieceType::J, PieceType::E,
-                      PieceType::J, PieceType::J, PieceType::E,
-                    ],
-                },
-            }
+            Piece::new(Vec2D {
+                n_rows: 3,
+                n_cols: 3,
+                data: vec![
+                  PieceType::E, PieceType::J, PieceType::E,
+                  PieceType::E, PieceType::J, PieceType::E,
+                  PieceType::J, PieceType::J, PieceType::E,
+                ],
+            })
+            .unwrap(),
         );
         map.insert(
             PieceType::L.as_ref(),
-            Piece {
-                shape: Vec2D {
-                    n_rows: 3,
-                    n_cols: 3,
-                    data: vec![
-                      PieceType::E, PieceType::L, PieceType::E,
-                      PieceType::E, PieceType::L, PieceType::E,
-                      PieceType::E, PieceType::L, PieceType::L,
-                    ],
-                },
-            }
+            Piece::new(Vec2D {
+                n_rows: 3,
+                n_cols: 3,
+                data: vec![
+                  PieceType::E, PieceType::L, PieceType::E,
+                  PieceType::E, PieceType::L, PieceType::E,
+                  PieceType::E, PieceType::L, PieceType::L,
+                ],
+            })
+            .unwrap(),
         );
         map.insert(
             PieceType::T.as_ref(),
-            Piece {
-                shape: Vec2D {
-                    n_rows: 3,
-                    n_cols: 3,
-                    data: vec![
-                      PieceType::E, PieceType::T, PieceType::E,
-                      PieceType::T, PieceType::T, PieceType::T,
-                      PieceType::E, PieceType::E, PieceType::E,
-                    ],
-                },
-            }
+            Piece::new(Vec2D {
+                n_rows: 3,
+                n_cols: 3,
+                data: vec![
+                  PieceType::E, PieceType::T, PieceType::E,
+                  PieceType::T, PieceType::T, PieceType::T,
+                  PieceType::E, PieceType::E, PieceType::E,
+                ],
+            })
+            .unwrap(),
         );
         map.insert(
             PieceType::O.as_ref(),
-            Piece {
-                shape: Vec2D {
-                    n_rows: 2,
-                    n_cols: 2,
-                    data: vec![
-                        PieceType::O, PieceType::O,
-                        PieceType::O, PieceType::O,
-                    ],
-                },
-            }
+            Piece::new(Vec2D {
+                n_rows: 2,
+                n_cols: 2,
+                data: vec![
+                    PieceType::O, PieceType::O,
+                    PieceType::O, PieceType::O,
+                ],
+            })
+            .unwrap(),
         );
         map.insert(
             PieceType::S.as_ref(),
-            Piece {
-                shape: Vec2D {
-                    n_rows: 3,
-                    n_cols: 3,
-                    data: vec![
-                        PieceType::E, PieceType::E, PieceType::E,
-                        PieceType::E, PieceType::S, PieceType::S,
-                        PieceType::S, PieceType::S, PieceType::E,
-                    ],
-                },
-            }
+            Piece::new(Vec2D {
+                n_rows: 3,
+                n_cols: 3,
+                data: vec![
+                    PieceType::E, PieceType::E, PieceType::E,
+                    PieceType::E, PieceType::S, PieceType::S,
+                    PieceType::S, PieceType::S, PieceType::E,
+                ],
+            })
+            .unwrap(),
         );
         map.insert(
             PieceType::Z.as_ref(),
-            Piece {
-                shape: Vec2D {
-                    n_rows: 3,
-                    n_cols: 3,
-                    data: vec![
-                        PieceType::E, PieceType::E, PieceType::E,
-                        PieceType::Z, PieceType::Z, PieceType::E,
-                        PieceType::E, PieceType::Z, PieceType::Z,
-                    ],
-                },
-            }
+            Piece::new(Vec2D {
+                n_rows: 3,
+                n_cols: 3,
+                data: vec![
+                    PieceType::E, PieceType::E, PieceType::E,
+                    PieceType::Z, PieceType::Z, PieceType::E,
+                    PieceType::E, PieceType::Z, PieceType::Z,
+                ],
+            })
+            .unwrap(),
         );
         map
     };
+    // spawned in place of pieces missing from PIECES
+    static ref FALLBACK_PIECE: Piece = Piece::new(Vec2D {
+        n_rows: 2,
+        n_cols: 2,
+        data: vec![PieceType::O; 4],
+    })
+    .unwrap();
 }
 
-const KEY: &'static str = "yew.tetris.self";
-const POSITION_INIT: Position = Position { x: 4, y: -1 };
-
+const KEY: &str = "yew.tetris.self";
+const HUD_KEY: &str = "yew.tetris.hud";
+const APPEARANCE_KEY: &str = "yew.tetris.appearance";
+const START_LEVEL_KEY: &str = "yew.tetris.start_level";
+const PREVIEW_COUNT_KEY: &str = "yew.tetris.preview_count";
+const COUNTDOWN_KEY: &str = "yew.tetris.countdown";
+const MARATHON_GOAL_KEY: &str = "yew.tetris.marathon_goal";
+const LOCK_RESET_CAP_KEY: &str = "yew.tetris.lock_reset_cap";
+const GRAVITY_FLOOR_KEY: &str = "yew.tetris.gravity_floor";
+const DAS_KEY: &str = "yew.tetris.das";
+const DAS_CUT_KEY: &str = "yew.tetris.das_cut";
+const ARR_KEY: &str = "yew.tetris.arr";
+const SOFT_DROP_KEY: &str = "yew.tetris.soft_drop";
+const THEME_KEY: &str = "yew.tetris.theme";
+const GRAVITY_PROFILE_KEY: &str = "yew.tetris.gravity_profile";
+const ROTATION_SYSTEM_KEY: &str = "yew.tetris.rotation_system";
+const HOLD_ROTATION_KEY: &str = "yew.tetris.hold_keeps_rotation";
+const MUTED_KEY: &str = "yew.tetris.muted";
+const ULTRA_BEST_KEY: &str = "yew.tetris.ultra_best";
+const KEY_BINDINGS_KEY: &str = "yew.tetris.key_bindings";
+const LEADERBOARD_KEY: &str = "yew.tetris.leaderboard";
+const LEADERBOARD_LEN: usize = 10;
+const SPRINT_TIMES_KEY: &str = "yew.tetris.sprint_times";
+const SPRINT_LINES: usize = 40;
+const GAMEPAD_POLL: u64 = 16; // ms
+const GAMEPAD_START: usize = 9;
+const CELL_SIZE: usize = 14; // px, as in the stylesheet
+const SWIPE_DISTANCE: f64 = 30.0; // px a finger moves before it counts as a swipe
+const FLICK_DURATION: f64 = 200.0; // ms, quicker downward swipes hard drop
+const ULTRA_SECONDS: u64 = 120;
+const MARATHON_GOAL: usize = 150; // lines
+const CHEESE_INTERVAL: u64 = 10; // seconds
+const GARBAGE_SENT: [usize; 5] = [0, 0, 1, 2, 4]; // versus garbage per rows cleared at once
+const COUNTDOWN_DEFAULT: u64 = 3; // seconds
+const MAX_COUNTDOWN: u64 = 9;
+const MAX_START_LEVEL: usize = 20;
+const MIN_DURATION: f64 = 16.0; // ms, one frame at 60fps
+const GRAVITY_FLOOR_DEFAULT: u64 = 30; // ms
+const GRAVITY_STEP: u64 = 16; // ms between checks for a row of gravity
+const STAGE_ROWS: usize = 20; // visible rows by default
+const STAGE_COLS: usize = 10;
+const BUFFER_ROWS: usize = 1; // hidden row above the stage that pieces spawn into
+const LAST_CLEAR_DURATION: u64 = 2000;
+const LAST_CLEAR_STEP: u64 = 500; // extra time per row when the delay scales
+const TRAIL_DURATION: u64 = 150;
+const RESTART_DELAY: u64 = 2000;
+const CONFIRM_RESTART_DELAY: u64 = 3000; // a second Restart click counts within it
+const BEGINNER_SAFETY_ROWS: usize = 4;
+const WELL_WARNING_DEPTH: usize = 3;
+const LOCK_DELAY: u64 = 500;
+const SOFT_DROP_FACTOR: f64 = 20.0; // times gravity while soft drop is held
+const DAS_DEFAULT: u64 = 170;
+const ARR_DEFAULT: u64 = 30;
+const PREVIEW_COUNT: usize = 3; // upcoming pieces shown beside the board by default
+const MAX_PREVIEW_COUNT: usize = 6;
+const MAX_LOCK_RESET_CAP: usize = 99;
 pub struct Model {
     link: ComponentLink<Self>,
     storage: StorageService,
     interval: IntervalService,
-    job: Option<Box<dyn Task>>,
+    jobs: Jobs,
     callback_tick: Callback<()>,
-    state: State,
+    timeout: TimeoutService,
+    playback: Option<Playback>, // the finished game being watched again
+    countdown: Option<u64>, // seconds left before the game starts or resumes
+    render: RenderService,
+    frame_job: Option<RenderTask>,
+    callback_frame: Callback<f64>,
+    inputs: Vec<Controls>,
+    // gravity runs off one steady GRAVITY_STEP interval: every tick adds the
+    // time since `last_tick_at` to `gravity_acc` and the piece falls a row for
+    // each full gravity duration in it. Moves and rotations leave both alone,
+    // a pause keeps what was accumulated and a new piece starts from zero.
+    last_tick_at: f64,
+    gravity_acc: f64, // ms
+    replay_text: String,
+    replay_error: Option<String>,
+    script_text: String,
+    script_error: Option<String>,
+    puzzle_text: String,
+    puzzle_error: Option<String>,
+    rebinding: Option<Controls>, // waiting for the key to play this action with
+    binding_error: Option<String>,
+    score_error: Option<String>, // why the last score was turned down
+    gamepad_buttons: Vec<bool>, // pressed at the last poll
+    swipe: Option<Swipe>,
+    canvas: NodeRef,
+    paint_job: Option<RenderTask>,
+    leaderboard: Leaderboard,
+    sprint_times: SprintTimes,
+    new_score: Option<Score>, // waiting for the player's name
+    auto_paused: bool, // paused by the tab being hidden, not the player
+    // the document's listeners, removed again in `destroy`
+    listeners: Vec<EventListenerHandle>,
+    remove_visibility_listener: Value, // a JS function
+    game: Game,
+    versus: Option<Versus>, // a local versus match, played instead of `game`
+}
+
+// the running timers, each one stops when its task is dropped
+#[derive(Default)]
+struct Jobs {
+    gravity: Option<Box<dyn Task>>,
+    clear: Option<Box<dyn Task>>,
+    trail: Option<Box<dyn Task>>,
+    restart: Option<Box<dyn Task>>,
+    playback: Option<Box<dyn Task>>,
+    lock: Option<Box<dyn Task>>,
+    shift: Option<Box<dyn Task>>, // DAS delay, then ARR repeats
+    soft_drop: Option<Box<dyn Task>>,
+    countdown: Option<Box<dyn Task>>,
+    gamepad: Option<Box<dyn Task>>,
+    confirm_restart: Option<Box<dyn Task>>, // running while a Restart waits to be confirmed
+    versus: Option<Box<dyn Task>>, // gravity of both local versus boards
+}
+
+impl Jobs {
+    // the timers of the game being played
+    fn stop_game(&mut self) {
+        self.gravity = None;
+        self.clear = None;
+        self.trail = None;
+        self.restart = None;
+        self.lock = None;
+        self.shift = None;
+        self.soft_drop = None;
+        self.countdown = None;
+    }
+
+    // every timer, for a model that's going away
+    fn stop_all(&mut self) {
+        *self = Jobs::default();
+    }
+}
+
+// a finger on the board, each SWIPE_DISTANCE it moves sideways or down from
+// the anchor is one step
+struct Swipe {
+    start: (f64, f64),
+    anchor: (f64, f64),
+    started_at: f64,
+    moved: bool,
 }
 
-#[derive(Debug, EnumIter, AsRefStr, Clone, PartialEq, Serialize, Deserialize)]
-enum PieceType {
+#[derive(Debug, EnumIter, AsRefStr, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PieceType {
+    #[serde(alias = "TMP")] // an unused cell kind older saves may still hold
     E,
     I,
     J,
@@ -157,22 +308,122 @@ enum PieceType {
     O,
     S,
     Z,
-    TMP,
+    G, // garbage
 }
 
 #[derive(Clone, Debug)]
-struct Piece {
+pub struct Piece {
     shape: Vec2D,
+    offsets: Vec<Vec<(usize, usize)>>, // occupied (row, col) cells of each orientation
+}
+
+impl Piece {
+    /// A piece drawn in `shape`. Shapes rotate within their grid, so it must
+    /// be square and have a cell for every row and column.
+    pub fn new(shape: Vec2D) -> Result<Piece, String> {
+        let Vec2D { n_rows, n_cols, .. } = shape;
+        if n_rows != n_cols {
+            return Err(format!("A {}x{} grid is not square", n_rows, n_cols));
+        }
+        let n_cells = shape.data.len();
+        if n_cells != n_rows * n_cols {
+            return Err(format!("{} cells don't fill a {}x{} grid", n_cells, n_rows, n_cols));
+        }
+        let mut offsets = Vec::new();
+        let mut rotated = shape.clone();
+        for _ in 0..4 {
+            offsets.push(rotated.occupied());
+            rotated = rotated.rotated();
+        }
+        Ok(Piece { shape, offsets })
+    }
+}
+
+/// The shapes pieces spawn with, keyed by piece type name ("I", "T", ...).
+/// Only the pieces in the set are dealt.
+#[derive(Clone, Debug)]
+pub struct PieceSet {
+    pieces: HashMap<String, Piece>,
+}
+
+impl PieceSet {
+    /// Checks a custom piece set, e.g. pentominoes drawn with the tetromino
+    /// letters.
+    pub fn new(pieces: HashMap<String, Piece>) -> Result<PieceSet, String> {
+        if pieces.is_empty() {
+            return Err("A piece set needs at least one piece".into());
+        }
+        for (name, piece) in &pieces {
+            let piece_type = match tetrominoes().find(|piece_type| piece_type.as_ref() == name) {
+                Some(piece_type) => piece_type,
+                None => return Err(format!("{} is not a piece type", name)),
+            };
+            if piece.offsets[0].is_empty() {
+                return Err(format!("The {} piece has no blocks", name));
+            }
+            if piece.shape.data.iter().any(|cell| *cell != PieceType::E && *cell != piece_type) {
+                return Err(format!("The {} piece has blocks of another piece", name));
+            }
+        }
+        Ok(PieceSet { pieces })
+    }
+
+    // the piece types dealt, in the order the randomizers draw from
+    fn types(&self) -> Vec<PieceType> {
+        tetrominoes()
+            .filter(|piece_type| self.pieces.contains_key(piece_type.as_ref()))
+            .collect()
+    }
+
+    // the spawn shape of a piece, None if the set doesn't have it
+    fn get(&self, piece_type: &PieceType) -> Option<Vec2D> {
+        self.pieces.get(piece_type.as_ref()).map(|piece| piece.shape.clone())
+    }
+
+    // the piece that spawns for `piece_type`: itself, or an O if the set has
+    // no shape for it, so a bad queue entry costs a piece rather than the game
+    fn spawn(&self, piece_type: PieceType) -> (PieceType, Vec2D) {
+        match self.get(&piece_type) {
+            Some(shape) => (piece_type, shape),
+            None => {
+                error!("No shape for piece {:?}, spawning an O instead", piece_type);
+                (PieceType::O, FALLBACK_PIECE.shape.clone())
+            }
+        }
+    }
+
+    /// The (row, column) of every block of a piece, within its grid, after
+    /// `rotation` quarter turns clockwise.
+    pub fn offsets(&self, piece_type: &PieceType, rotation: usize) -> &[(usize, usize)] {
+        let piece = self.pieces.get(piece_type.as_ref()).unwrap_or(&FALLBACK_PIECE);
+        &piece.offsets[rotation % 4]
+    }
+}
+
+impl Default for PieceSet {
+    // the seven standard tetrominoes
+    fn default() -> PieceSet {
+        PieceSet {
+            pieces: PIECES
+                .iter()
+                .map(|(name, piece)| (name.to_string(), piece.clone()))
+                .collect(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct Vec2D {
+pub struct Vec2D {
     n_rows: usize,        // number of rows
     n_cols: usize,        // number of columns (redundant, since we know the length of data)
     data: Vec<PieceType>, // data stored in a contiguous 1D array
 }
 
 impl Vec2D {
+    pub fn new(n_rows: usize, n_cols: usize, data: Vec<PieceType>) -> Vec2D {
+        Vec2D { n_rows, n_cols, data }
+    }
+
     fn set(&mut self, row: usize, col: usize, piece: &str) {
         let piece = PieceType::iter().find(|p| p.as_ref() == piece);
         if let Some(piece) = piece {
@@ -193,730 +444,4894 @@ impl Vec2D {
         assert!(col < self.n_cols);
         self.data[row * self.n_cols + col].as_ref()
     }
+
+    fn occupied(&self) -> Vec<(usize, usize)> {
+        let mut cells = Vec::new();
+        for row in 0..self.n_rows {
+            for col in 0..self.n_cols {
+                if self.data[row * self.n_cols + col] != PieceType::E {
+                    cells.push((row, col));
+                }
+            }
+        }
+        cells
+    }
+
+    // clockwise quarter turn
+    fn rotated(&self) -> Vec2D {
+        let mut rotated_data: Vec<PieceType> = Vec::new();
+        for n_col in 0..self.n_cols {
+            for n_row in (0..self.n_rows).rev() {
+                rotated_data.push(self.get_piece_type(n_row, n_col));
+            }
+        }
+        Vec2D {
+            n_rows: self.n_rows,
+            n_cols: self.n_cols,
+            data: rotated_data,
+        }
+    }
+
+    // counterclockwise quarter turn
+    fn rotated_ccw(&self) -> Vec2D {
+        let mut rotated_data: Vec<PieceType> = Vec::new();
+        for n_col in (0..self.n_cols).rev() {
+            for n_row in 0..self.n_rows {
+                rotated_data.push(self.get_piece_type(n_row, n_col));
+            }
+        }
+        Vec2D {
+            n_rows: self.n_rows,
+            n_cols: self.n_cols,
+            data: rotated_data,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 struct Position {
     x: isize,
     y: isize,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 struct Player {
     piece_type: PieceType,
     piece_shape: Vec2D,
+    rotation: usize, // quarter turns clockwise from the spawn orientation
     position: Position,
     collided: bool,
+    last_kick: Option<usize>, // kick of the last rotation, cleared by any other move
+    #[serde(default)]
+    quarter_kick: bool, // whether that kick came from the SRS quarter turn tables
+    inputs: usize, // shift and rotate presses spent on this piece, for finesse
 }
 
-#[derive(Serialize, Deserialize)]
-struct GameStatus {
-    level: usize,
-    rows_cleared: usize,
-    score: usize,
-    game_over: bool,
+impl Player {
+    // whether the piece, placed at (x, y), fills the given stage cell
+    fn fills(&self, x: isize, y: isize, row: isize, col: isize) -> bool {
+        let (n_row, n_col) = (row - y, col - x);
+        n_row >= 0
+            && n_col >= 0
+            && (n_row as usize) < self.piece_shape.n_rows
+            && (n_col as usize) < self.piece_shape.n_cols
+            && self.piece_shape.get(n_row as usize, n_col as usize) != PieceType::E.as_ref()
+    }
 }
 
+// cells a hard-dropped piece fell through, from its top row before the drop
+// down to its top row after landing
 #[derive(Serialize, Deserialize)]
-pub struct State {
-    entries: Vec<Entry>,
-    filter: Filter,
-    value: String,
-    edit_value: String,
-    stage: Vec2D,
-    player: Player,
-    game_status: GameStatus,
+struct Trail {
+    cols: Vec<isize>,
+    top: isize,
+    bottom: isize,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Entry {
-    description: String,
-    completed: bool,
-    editing: bool,
-}
+impl Trail {
+    fn from_drop(player: &Player, start_y: isize) -> Option<Trail> {
+        let Vec2D { n_rows, n_cols, .. } = player.piece_shape.clone();
+        let occupied = |row: usize, col: usize| player.piece_shape.get(row, col) != PieceType::E.as_ref();
+        let cols: Vec<isize> = (0..n_cols)
+            .filter(|&col| (0..n_rows).any(|row| occupied(row, col)))
+            .map(|col| player.position.x + col as isize)
+            .collect();
+        let first_row = (0..n_rows).find(|&row| (0..n_cols).any(|col| occupied(row, col)))? as isize;
 
-pub enum Controls {
-    Left,
-    Right,
-    Down,
-    Bottom,
-    Rotate,
-    Pause,
+        if player.position.y <= start_y {
+            return None;
+        }
+        Some(Trail {
+            cols,
+            top: start_y + first_row,
+            bottom: player.position.y + first_row,
+        })
+    }
+
+    // the trail fades out the further it is from the landing spot
+    fn opacity(&self, row: isize, col: isize) -> Option<f64> {
+        if self.cols.contains(&col) && row >= self.top && row < self.bottom {
+            Some((row - self.top + 1) as f64 / (self.bottom - self.top + 1) as f64)
+        } else {
+            None
+        }
+    }
 }
 
-pub enum Msg {
-    Move(Controls),
-    StartPause,
-    StartInterval,
-    Cancel,
-    Tick,
+// board look, handed to the stylesheet as CSS custom properties
+#[derive(Clone, Serialize, Deserialize)]
+struct Appearance {
+    cell_gap: usize,    // px
+    cell_radius: usize, // px
+    background: String,
 }
 
-fn initialize_stage(rows: usize, columns: usize) -> Vec2D {
-    let stage: Vec2D = Vec2D {
-        n_rows: rows,
-        n_cols: columns,
-        data: (0..rows * columns).map(|_| PieceType::E).collect(),
-    };
-    stage
+impl Default for Appearance {
+    fn default() -> Self {
+        Appearance {
+            cell_gap: 2,
+            cell_radius: 0,
+            background: "lightgray".into(),
+        }
+    }
 }
 
-fn initialize_player() -> Player {
-    let random_piece: PieceType = get_random_piece();
-    let piece_shape = PIECES.get(random_piece.as_ref()).unwrap().shape.clone();
-    let player: Player = Player {
-        piece_type: random_piece,
-        piece_shape: piece_shape,
-        position: POSITION_INIT,
-        collided: false,
-    };
-    player
+impl Appearance {
+    fn style(&self) -> String {
+        format!(
+            "--cell-gap: {}px; --cell-radius: {}px; --board-background: {};",
+            self.cell_gap, self.cell_radius, self.background
+        )
+    }
 }
 
-fn initialize_game_status() -> GameStatus {
-    let game: GameStatus = GameStatus {
-        level: 16,
-        rows_cleared: 0,
-        score: 0,
-        game_over: false,
-    };
-    game
+// the key each action is played with, one key per action
+#[derive(Clone, Serialize, Deserialize)]
+struct KeyBindings {
+    keys: Vec<(Controls, String)>,
 }
 
-fn get_random_piece() -> PieceType {
-    let mut rng = rand::thread_rng();
-    let num = rng.gen_range(0, 7);
-    info!("random number: {}", num);
-    let piece: PieceType = match num {
-        0 => PieceType::I,
-        1 => PieceType::J,
-        2 => PieceType::L,
-        3 => PieceType::T,
-        4 => PieceType::O,
-        5 => PieceType::S,
-        _ => PieceType::Z,
-    };
-    piece
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            keys: vec![
+                (Controls::Left, "ArrowLeft".into()),
+                (Controls::Right, "ArrowRight".into()),
+                (Controls::SoftDrop, "ArrowDown".into()),
+                (Controls::Bottom, " ".into()),
+                (Controls::Rotate, "ArrowUp".into()),
+                (Controls::RotateCCW, "z".into()),
+                (Controls::Rotate180, "a".into()),
+                (Controls::Hold, "c".into()),
+                (Controls::Pause, "p".into()),
+                (Controls::Undo, "u".into()),
+                (Controls::Redo, "y".into()),
+                (Controls::Branch, "b".into()),
+            ],
+        }
+    }
 }
 
-pub fn fibonacci(n: usize) -> f64 {
-    let n = n + 3;
-    if n == 0 {
-        panic!("zero is not a right argument to fibonacci()!");
-    } else if n == 1 {
-        return 1.0;
+impl KeyBindings {
+    fn control(&self, key: &str) -> Option<Controls> {
+        self.keys
+            .iter()
+            .find(|(_, bound)| bound == key)
+            .map(|(control, _)| control.clone())
     }
 
-    let mut sum = 0.0;
-    let mut last = 0.0;
-    let mut curr = 1.0;
-    for _ in 1..n + 1 {
-        sum = last + (curr / 2.0);
-        last = curr;
-        curr = sum;
+    fn key(&self, control: &Controls) -> &str {
+        self.keys
+            .iter()
+            .find(|(bound_control, _)| bound_control == control)
+            .map_or("", |(_, key)| key.as_str())
     }
 
-    sum
+    // refuses a key another action is already played with
+    fn bind(&mut self, control: &Controls, key: String) -> Result<(), Controls> {
+        match self.control(&key) {
+            Some(other) if other != *control => Err(other),
+            _ => {
+                for (bound_control, bound) in self.keys.iter_mut() {
+                    if bound_control == control {
+                        *bound = key.clone();
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
 }
 
-fn get_duration(level: usize) -> f64 {
-    let mut sum: f64 = 1000.0;
-    for i in 6..7 + level {
-        sum = sum - (1000.0 / fibonacci(i));
+fn key_name(key: &str) -> &str {
+    match key {
+        " " => "Space",
+        key => key,
     }
-    info!("final sum: {}", sum);
-    sum
 }
 
-impl Component for Model {
-    type Message = Msg;
-    type Properties = ();
-
-    fn create(_: Self::Properties, link: ComponentLink<Self>) -> Self {
-        let storage = StorageService::new(Area::Local);
+// the keys of each local versus player, player two on the left of the keyboard
+const VERSUS_KEYS: [[(&str, Controls); 6]; 2] = [
+    [
+        ("ArrowLeft", Controls::Left),
+        ("ArrowRight", Controls::Right),
+        ("ArrowDown", Controls::SoftDrop),
+        ("ArrowUp", Controls::Rotate),
+        ("Enter", Controls::Bottom),
+        (".", Controls::Hold),
+    ],
+    [
+        ("a", Controls::Left),
+        ("d", Controls::Right),
+        ("s", Controls::SoftDrop),
+        ("w", Controls::Rotate),
+        (" ", Controls::Bottom),
+        ("q", Controls::Hold),
+    ],
+];
 
-        let interval = IntervalService::new();
+// which versus player a key belongs to, and what it does for them
+fn versus_control(key: &str) -> Option<(usize, Controls)> {
+    VERSUS_KEYS.iter().enumerate().find_map(|(player, keys)| {
+        keys.iter()
+            .find(|(bound, _)| *bound == key)
+            .map(|(_, control)| (player, control.clone()))
+    })
+}
 
-        let entries = {
-            if let Json(Ok(restored_model)) = storage.restore(KEY) {
-                restored_model
-            } else {
-                Vec::new()
+// buttons of the first connected gamepad, none if there is no gamepad or the
+// browser lacks the Gamepad API
+fn gamepad_buttons() -> Vec<bool> {
+    let buttons = js! {
+        var pads = navigator.getGamepads ? navigator.getGamepads() : [];
+        for (var i = 0; i < pads.length; i++) {
+            if (pads[i] && pads[i].connected) {
+                return Array.prototype.map.call(pads[i].buttons, function(button) {
+                    return button.pressed;
+                });
             }
-        };
+        }
+        return [];
+    };
+    buttons.try_into().unwrap_or_default()
+}
 
-        let state = State {
-            entries,
-            filter: Filter::All,
-            value: "".into(),
-            edit_value: "".into(),
-            stage: initialize_stage(21, 10),
-            player: initialize_player(),
-            game_status: initialize_game_status(),
-        };
+fn on_board(target: Option<EventTarget>) -> bool {
+    within(target, ".board")
+}
 
-        let mut link_clone = link.clone();
-        document().add_event_listener(move |event: KeyDownEvent| {
-            if event.key() == "Enter" {
-                link_clone.send_message(Msg::StartPause);
-            } else if event.key() == "ArrowRight" {
-                info!("Right key pressed");
-                link_clone.send_message_batch(vec![
-                    Msg::Move(Controls::Right),
-                    Msg::Cancel,
-                    Msg::StartInterval,
-                ]);
-            } else if event.key() == "ArrowLeft" {
-                info!("Left key pressed");
-                link_clone.send_message_batch(vec![
-                    Msg::Move(Controls::Left),
-                    Msg::Cancel,
-                    Msg::StartInterval,
-                ]);
-            } else if event.key() == "ArrowDown" {
-                info!("Down key pressed");
-                link_clone.send_message_batch(vec![
-                    Msg::Move(Controls::Bottom),
-                    Msg::Cancel,
-                    Msg::StartInterval,
-                ]);
-            } else if event.key() == "ArrowUp" {
-                // TODO when checking for colision on rotation, if bottom is not allowed, move up
-                info!("Up key pressed");
-                // TODO only cancel/start interval when next down will colide
-                link_clone.send_message_batch(vec![
-                    Msg::Move(Controls::Rotate),
-                    Msg::Cancel,
-                    Msg::StartInterval,
-                ]);
-                // TODO allow rotation when position is < 0 || > max
-            }
-        });
+// keys typed into a field are text, not moves
+fn in_text_field(target: Option<EventTarget>) -> bool {
+    within(target, "input[type=text], input[type=number], textarea")
+}
 
-        Model {
-            link: link.clone(),
-            storage,
-            state,
-            interval,
-            callback_tick: link.callback(|_| Msg::Tick),
-            job: None,
-        }
+fn within(target: Option<EventTarget>, selectors: &str) -> bool {
+    target
+        .and_then(|target| TryInto::<Element>::try_into(target).ok())
+        .and_then(|element| element.closest(selectors).ok().flatten())
+        .is_some()
+}
+
+fn touch_point(touches: Vec<Touch>) -> (f64, f64) {
+    touches
+        .first()
+        .map_or((0.0, 0.0), |touch| (touch.client_x(), touch.client_y()))
+}
+
+// the standard gamepad layout, the start button is handled on its own
+fn gamepad_control(button: usize) -> Option<Controls> {
+    match button {
+        0 => Some(Controls::Rotate),
+        1 => Some(Controls::RotateCCW),
+        3 => Some(Controls::Rotate180),
+        2 | 4 | 5 => Some(Controls::Hold),
+        8 => Some(Controls::Pause),
+        12 => Some(Controls::Bottom),
+        13 => Some(Controls::SoftDrop),
+        14 => Some(Controls::Left),
+        15 => Some(Controls::Right),
+        _ => None,
     }
+}
 
-    fn update(&mut self, msg: Self::Message) -> ShouldRender {
-        match msg {
-            Msg::StartPause => {
-                if self.job.is_none() {
-                    if self.state.game_status.game_over {
-                        self.state.initialize_game();
-                    }
-                    info!("Starting game!");
-                    self.link.send_message(Msg::StartInterval);
-                } else {
-                    info!("Pausing game");
-                    self.link.send_message(Msg::Cancel);
-                }
-            }
-            Msg::StartInterval => {
-                {
-                    let duration: u64 = get_duration(self.state.game_status.level) as u64;
-                    info!("Duration: {}", duration);
-                    let handle = self
-                        .interval
-                        .spawn(Duration::from_millis(duration), self.callback_tick.clone());
-                    self.job = Some(Box::new(handle));
-                }
-                info!("Interval started!");
-            }
-            Msg::Cancel => {
-                if let Some(mut task) = self.job.take() {
-                    task.cancel();
-                }
-                info!("Canceled");
-                if self.job.is_none() {
-                    info!("Job still exists!");
-                }
-            }
-            Msg::Tick => {
-                info!("Tick..");
-                self.link.send_message(Msg::Move(Controls::Down));
-            }
-            Msg::Move(control) => {
-                if !self.state.game_status.game_over {
-                    match control {
-                        Controls::Left => {
-                            if self.is_move_allowed(Controls::Left, None) {
-                                self.state.player.position.x = self.state.player.position.x - 1
-                            }
-                        }
-                        Controls::Right => {
-                            if self.is_move_allowed(Controls::Right, None) {
-                                self.state.player.position.x = self.state.player.position.x + 1
-                            }
-                        }
-                        Controls::Bottom => loop {
-                            if self.is_move_allowed(Controls::Down, None) {
-                                self.state.player.position.y = self.state.player.position.y + 1
-                            } else {
-                                if self.state.player.position.y <= 0 {
-                                    self.state.game_over();
-                                    self.link.send_message(Msg::Cancel);
-                                } else {
-                                    self.state.add_player_piece_stage();
-
-                                    let rows = self.get_completed_rows();
-                                    if rows.len() != 0 {
-                                        self.state.update_game_state(rows.len());
-                                        self.state.remove_rows(rows);
-                                    }
-                                }
-                                break;
-                            }
-                        },
-                        Controls::Down => {
-                            if self.is_move_allowed(Controls::Down, None) {
-                                self.state.player.position.y = self.state.player.position.y + 1
-                            } else {
-                                if self.state.player.position.y <= 0 {
-                                    self.state.game_over();
-                                    self.link.send_message(Msg::Cancel);
-                                } else {
-                                    self.state.add_player_piece_stage();
-
-                                    let rows = self.get_completed_rows();
-                                    if rows.len() != 0 {
-                                        self.state.update_game_state(rows.len());
-                                        self.state.remove_rows(rows);
-                                    }
-                                }
-                            }
-                        }
-                        Controls::Rotate => {
-                            if self.is_move_allowed(Controls::Rotate, None) {
-                                self.state.rotate_player_piece();
-                            } else {
-                                let position = Position {
-                                    x: self.state.player.position.x,
-                                    y: self.state.player.position.y - 1,
-                                };
-                                if self.is_move_allowed(Controls::Rotate, Some(position)) {
-                                    self.state.rotate_player_piece();
-                                }
-                            }
-                        }
-                        Controls::Pause => todo!(),
-                    }
-                }
+#[derive(Clone, Serialize, Deserialize)]
+struct Score {
+    score: usize,
+    level: usize,
+    lines: usize,
+    name: Option<String>,
+    #[serde(default)]
+    replay: Option<Replay>, // the game that made the score, to check it against
+}
+
+// best scores first
+#[derive(Default, Serialize, Deserialize)]
+struct Leaderboard {
+    entries: Vec<Score>,
+}
+
+impl Leaderboard {
+    // a full table takes any score that beats its lowest entry
+    fn qualifies(&self, score: usize) -> bool {
+        score > 0
+            && (self.entries.len() < LEADERBOARD_LEN
+                || self.entries.last().is_some_and(|lowest| score > lowest.score))
+    }
+
+    // only takes a score its replay plays back to
+    fn submit(&mut self, score: Score) -> Result<(), String> {
+        match score.replay.as_ref().map(verify_replay) {
+            Some(Some(points)) if points == score.score => {
+                self.insert(score);
+                Ok(())
             }
+            Some(Some(_)) => Err(format!("The replay doesn't play back to {} points", score.score)),
+            Some(None) => Err("A game dealt by custom pieces can't be checked".into()),
+            None => Err("A score needs the replay of its game".into()),
         }
-        self.storage.store(KEY, Json(&self.state.entries));
-        true
     }
 
-    fn view(&self) -> Html {
-        html! {
-            <div>
-                <p>{ format!("Level: {}", self.state.game_status.level) }</p>
-                <p>{ format!("Rows cleared: {}", self.state.game_status.rows_cleared) }</p>
-                <p>{ format!("Score: {}", self.state.game_status.score) }</p>
-                <table>
-                <>
-            { for (0..self.state.stage.n_rows).map(|row| {
-                html! {
-                  <>
-                    <tr>
-                    {
-                        for (0..self.state.stage.n_cols).map(|col| {
-                            let mut cell = self.state.stage.get(row, col);
-                            let Position { x, y } = self.state.player.position;
-                            let Vec2D { n_rows, n_cols, data } = self.state.player.piece_shape.clone();
-                            let n_rows = n_rows as isize;
-                            let n_cols = n_cols as isize;
-                            let rowi = row as isize;
-                            let coli = col as isize;
-                            if (y..y + n_rows).contains(&rowi) {
-                                if (x..x + n_cols).contains(&coli) {
-                                    let player_cell = self.state.player.piece_shape.get((rowi - y) as usize, (coli - x) as usize);
-                                    if player_cell != PieceType::E.as_ref() {
-                                        cell = player_cell;
-                                    }
-                                }
-                            }
+    fn insert(&mut self, score: Score) {
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.score < score.score)
+            .unwrap_or(self.entries.len());
+        self.entries.insert(index, score);
+        self.entries.truncate(LEADERBOARD_LEN);
+    }
+}
 
-                            html! {
-                                <td class=format!("cell-{}", cell)/>
-                            }
-                        })
-                    }
-                    </tr>
-                  </>
-                }
-            })}
-              </>
-              </table>
-            { if self.state.game_status.game_over {
-                html! {
-                    <>
-                    <div class="game-over">
-                    {"Game Over"}
-                    </div>
-                        <p>{"Press Enter to start over"}</p>
-                        </>
-                }
-            } else {
-                html! {}
-            }
-            }
-            </div>
-        }
+// ms a Sprint took, fastest first
+#[derive(Default, Serialize, Deserialize)]
+struct SprintTimes {
+    times: Vec<f64>,
+}
+
+impl SprintTimes {
+    // the time's rank, if it made the table
+    fn insert(&mut self, time: f64) -> Option<usize> {
+        let index = self
+            .times
+            .iter()
+            .position(|entry| *entry > time)
+            .unwrap_or(self.times.len());
+        self.times.insert(index, time);
+        self.times.truncate(LEADERBOARD_LEN);
+        Some(index + 1).filter(|_| index < LEADERBOARD_LEN)
     }
 }
 
-impl Model {
-    fn is_position_empty(&self, x: isize, y: isize, player_piece: Option<Vec2D>) -> bool {
-        let piece: Vec2D;
-        if let Some(player_piece) = player_piece {
-            piece = player_piece;
-        } else {
-            piece = self.state.player.piece_shape.clone();
-        }
-        let Vec2D {
-            n_rows: stage_rows,
-            n_cols: stage_cols,
-            ..
-        } = self.state.stage.clone();
-        let Vec2D {
-            n_rows: player_rows,
-            n_cols: player_cols,
-            ..
-        } = piece;
-        let player_rows = player_rows as isize;
-        let player_cols = player_cols as isize;
-        let stage_rows = stage_rows as isize;
-        let stage_cols = stage_cols as isize;
-
-        for n_row in 0..player_rows {
-            for n_col in 0..player_cols {
-                let row = n_row + y;
-                let col = n_col + x;
-
-                if row < 0 || row >= stage_rows || col < 0 || col >= stage_cols {
-                    info!("nope");
-                } else {
-                    let stage_cell = self.state.stage.get(row as usize, col as usize);
-                    let player_cell = piece.get(n_row as usize, n_col as usize);
-                    if stage_cell != PieceType::E.as_ref() && player_cell != PieceType::E.as_ref() {
-                        return false;
-                    }
-                }
-            }
-        }
+// minutes, seconds and ms, the way Sprint times are shown
+fn format_time(ms: f64) -> String {
+    let seconds = ms / 1000.0;
+    format!("{}:{:06.3}", (seconds / 60.0) as u64, seconds % 60.0)
+}
 
-        true
+// short synthesized tones, so no audio files need to be served
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sound {
+    Rotate,
+    Lock,
+    HardDrop,
+    Clear,
+    Tetris,
+    LevelUp,
+    GameOver,
+}
+
+impl Sound {
+    // oscillator wave, frequency in Hz and length in seconds
+    fn tone(self) -> (&'static str, f64, f64) {
+        match self {
+            Sound::Rotate => ("square", 660.0, 0.04),
+            Sound::Lock => ("triangle", 220.0, 0.08),
+            Sound::HardDrop => ("triangle", 110.0, 0.12),
+            Sound::Clear => ("square", 523.0, 0.2),
+            Sound::Tetris => ("sawtooth", 784.0, 0.4),
+            Sound::LevelUp => ("square", 988.0, 0.3),
+            Sound::GameOver => ("sawtooth", 98.0, 0.8),
+        }
     }
 
-    fn is_player_position_valid(&self, x: isize, y: isize, player_piece: Option<Vec2D>) -> bool {
-        let piece: Vec2D;
-        if let Some(player_piece) = player_piece {
-            piece = player_piece;
-        } else {
-            piece = self.state.player.piece_shape.clone();
-        }
-        let Vec2D {
-            n_rows: stage_rows,
-            n_cols: stage_cols,
-            ..
-        } = self.state.stage.clone();
-        let Vec2D {
-            n_rows: player_rows,
-            n_cols: player_cols,
-            ..
-        } = piece;
-        let player_rows = player_rows as isize;
-        let player_cols = player_cols as isize;
-        let stage_rows = stage_rows as isize;
-        let stage_cols = stage_cols as isize;
-
-        // check if piece ouside left border of stage
-        if x < 0 {
-            let distance: isize = x as isize / -1;
-            for n_row in 0..player_rows {
-                for n_col in 0..distance {
-                    let cell = piece.get(n_row as usize, n_col as usize);
-                    if cell != PieceType::E.as_ref() {
-                        return false;
-                    }
-                }
+    // what a step of the engine sounds like, if anything
+    fn after(
+        rotated: bool,
+        hard_dropped: bool,
+        level_up: bool,
+        outcome: &StepOutcome,
+    ) -> Option<Sound> {
+        match outcome {
+            StepOutcome::GameOver => Some(Sound::GameOver),
+            StepOutcome::Locked { .. } if level_up => Some(Sound::LevelUp),
+            StepOutcome::Locked { rows_cleared: 4 } => Some(Sound::Tetris),
+            StepOutcome::Locked { rows_cleared } if *rows_cleared > 0 => Some(Sound::Clear),
+            StepOutcome::Locked { .. } if hard_dropped => Some(Sound::HardDrop),
+            StepOutcome::Locked { .. } => Some(Sound::Lock),
+            StepOutcome::Moved | StepOutcome::Grounded | StepOutcome::LockReset if rotated => {
+                Some(Sound::Rotate)
             }
+            _ => None,
         }
+    }
 
-        // check if piece ouside right border of stage
-        if x + player_cols > stage_cols {
-            let distance: isize = x + player_cols - stage_cols;
-            for n_row in 0..player_rows {
-                for n_col in (player_cols - distance)..player_cols {
-                    let cell = piece.get(n_row as usize, n_col as usize);
-                    if cell != PieceType::E.as_ref() {
-                        return false;
-                    }
+    // a new sound cuts off the one still playing, so quick inputs don't pile
+    // up; browsers without Web Audio stay silent
+    fn play(self) {
+        let (wave, frequency, seconds) = self.tone();
+        js! { @(no_return)
+            try {
+                var audio = window.tetrisAudio;
+                if (!audio) {
+                    var AudioContext = window.AudioContext || window.webkitAudioContext;
+                    audio = window.tetrisAudio = { context: new AudioContext(), voice: null };
                 }
-            }
+                if (audio.voice) {
+                    try { audio.voice.stop(); } catch (error) {}
+                }
+                var context = audio.context;
+                var now = context.currentTime;
+                var oscillator = context.createOscillator();
+                var gain = context.createGain();
+                oscillator.type = @{wave};
+                oscillator.frequency.value = @{frequency};
+                gain.gain.setValueAtTime(0.2, now);
+                gain.gain.exponentialRampToValueAtTime(0.001, now + @{seconds});
+                oscillator.connect(gain);
+                gain.connect(context.destination);
+                oscillator.start(now);
+                oscillator.stop(now + @{seconds});
+                audio.voice = oscillator;
+            } catch (error) {}
         }
+    }
+}
+
+// the board is a table by default, a canvas keeps the DOM the same size
+// however large the board gets
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Renderer {
+    Table,
+    Canvas,
+}
+
+// piece palettes, each with its own set of cell classes in the stylesheet
+#[derive(Debug, AsRefStr, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Theme {
+    Classic,
+    Neon,
+    Monochrome,
+}
+
+impl Theme {
+    fn next(self) -> Theme {
+        match self {
+            Theme::Classic => Theme::Neon,
+            Theme::Neon => Theme::Monochrome,
+            Theme::Monochrome => Theme::Classic,
+        }
+    }
+
+    // `cell` is a piece type or "ghost"
+    fn class(self, cell: &str) -> String {
+        match self {
+            Theme::Classic => format!("cell-{}", cell),
+            Theme::Neon => format!("cell-neon-{}", cell),
+            Theme::Monochrome => format!("cell-mono-{}", cell),
+        }
+    }
+
+    // the canvas renderer's copy of the stylesheet colors
+    fn color(self, cell: &str) -> Option<&'static str> {
+        let colors = match self {
+            Theme::Classic => [
+                "red", "green", "yellow", "violet", "orange", "skyblue", "darkblue",
+                "gray", "rgba(0, 0, 0, 0.15)",
+            ],
+            Theme::Neon => [
+                "#ff0055", "#39ff14", "#fff01f", "#bc13fe", "#ff9f00", "#00f0ff", "#2d5bff",
+                "#808080", "rgba(255, 255, 255, 0.25)",
+            ],
+            Theme::Monochrome => [
+                "#222", "#444", "#666", "#333", "#555", "#111", "#777",
+                "#999", "rgba(0, 0, 0, 0.15)",
+            ],
+        };
+        let index = match cell {
+            "Z" => 0,
+            "S" => 1,
+            "O" => 2,
+            "T" => 3,
+            "L" => 4,
+            "I" => 5,
+            "J" => 6,
+            "G" => 7,
+            "ghost" => 8,
+            _ => return None,
+        };
+        Some(colors[index])
+    }
+}
+
+// a cell taken by a piece, not empty
+fn is_block(cell: &str) -> bool {
+    cell != PieceType::E.as_ref()
+}
+
+// how much of the HUD is rendered around the board, Minimal and None are meant
+// for streaming/recording
+#[derive(Debug, AsRefStr, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum HudMode {
+    Full,
+    Minimal,
+    None,
+}
+
+#[derive(Clone, Copy)]
+enum HudSection {
+    Stats,
+    Score,
+    Settings,
+    Preview,
+    Hold,
+    Leaderboard,
+}
+
+impl HudMode {
+    fn next(self) -> HudMode {
+        match self {
+            HudMode::Full => HudMode::Minimal,
+            HudMode::Minimal => HudMode::None,
+            HudMode::None => HudMode::Full,
+        }
+    }
+
+    fn shows(self, section: HudSection) -> bool {
+        match self {
+            HudMode::Full => true,
+            HudMode::Minimal => {
+                matches!(section, HudSection::Score | HudSection::Preview | HudSection::Hold)
+            }
+            HudMode::None => false,
+        }
+    }
+}
+
+#[derive(Debug, AsRefStr, Clone, PartialEq, Serialize, Deserialize)]
+enum ClearKind {
+    Single,
+    Double,
+    Triple,
+    Tetris,
+    #[strum(serialize = "Mini T-Spin Single")]
+    MiniTSpinSingle,
+    #[strum(serialize = "Mini T-Spin Double")]
+    MiniTSpinDouble,
+    #[strum(serialize = "T-Spin Single")]
+    TSpinSingle,
+    #[strum(serialize = "T-Spin Double")]
+    TSpinDouble,
+    #[strum(serialize = "T-Spin Triple")]
+    TSpinTriple,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TSpin {
+    Mini,
+    Full,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct GameStatus {
+    level: usize,
+    start_level: usize, // the level never drops below the one the game started on
+    rows_cleared: usize,
+    score: usize,
+    combo: isize, // -1 until a piece clears rows
+    back_to_back: bool, // the last clear was a difficult one
+    finesse_faults: usize, // pieces placed with more presses than needed
+    game_over: bool,
+    game_won: bool, // ended by reaching the line goal, game_over is set too
+}
+
+impl GameStatus {
+    // level L + 1 is reached at 10 * L lines, however high the game started
+    fn lines_to_next_level(&self) -> usize {
+        (self.level * 10).saturating_sub(self.rows_cleared)
+    }
+}
+
+// ms of play for the replay timestamps and the game clock, it only runs
+// between `start` and `stop`, so countdowns and pauses are left out
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct PlayClock {
+    played: f64, // ms, up to the last stop
+    #[serde(skip)]
+    running_since: Option<f64>, // Date::now() at the last start
+}
+
+impl PlayClock {
+    fn start(&mut self, now: f64) {
+        if self.running_since.is_none() {
+            self.running_since = Some(now);
+        }
+    }
+
+    fn stop(&mut self, now: f64) {
+        self.played = self.at(now);
+        self.running_since = None;
+    }
+
+    fn at(&self, now: f64) -> f64 {
+        self.played + self.running_since.map_or(0.0, |since| now - since)
+    }
+
+    fn seconds(&self, now: f64) -> u64 {
+        (self.at(now) / 1000.0) as u64
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct State {
+    stage: Vec2D,
+    player: Player,
+    game_status: GameStatus,
+    blind_mode: bool,
+    announcement: String,
+    last_clear: Option<ClearKind>,
+    perfect_clear: bool, // shown for as long as the last clear
+    trail: Option<Trail>,
+    trail_enabled: bool,
+    hud: HudMode,
+    droughts: HashMap<PieceType, usize>,
+    piece_counts: HashMap<PieceType, usize>, // pieces of each type dealt this game
+    auto_restart: bool,
+    appearance: Appearance,
+    safety_rows: usize, // hidden rows above the visible stage, a beginner handicap
+    safe_first_piece: bool,
+    next_queue: Vec<PieceType>,
+    held_piece: Option<PieceType>,
+    #[serde(default)]
+    held_rotation: usize, // quarter turns the held piece comes back with
+    hold_used: bool, // only one hold until the current piece locks
+    #[serde(default)]
+    hold_keeps_rotation: bool,
+    scaled_clear_delay: bool,
+    randomizer: Randomizer,
+    ghost_enabled: bool,
+    paused: bool,
+    lock_resets: usize, // lock delay restarts used up by the current piece
+    lock_reset_cap: usize,
+    rotation_system: RotationSystem,
+    #[serde(skip)]
+    held_directions: Vec<Controls>, // held Left/Right keys, the last one wins
+    #[serde(skip)]
+    soft_dropping: bool, // soft drop key held
+    das_ms: u64,
+    #[serde(default)]
+    das_cut_ms: u64, // gravity held off when a DAS charge starts, 0 for none
+    gravity_floor_ms: u64, // the fastest guideline gravity gets, the NES profile ignores it
+    gravity_profile: GravityProfile,
+    arr_ms: u64,
+    soft_drop_ms: u64, // per row while soft drop is held, 0 follows gravity
+    seed: Option<u64>,
+    start_level: usize,
+    stage_rows: usize, // visible rows, the stage also has buffer and safety rows
+    stage_cols: usize,
+    mode: GameMode, // of the game being played
+    next_mode: GameMode, // what CycleMode picks, the next game is played in it
+    elapsed: u64, // seconds, one for each the play clock ran past
+    #[serde(default)]
+    played: PlayClock,
+    time_left: Option<u64>, // seconds
+    marathon_goal: usize, // lines, 0 for an endless Marathon, from the next game on
+    lines_goal: Option<usize>, // lines that win the current game
+    ultra_best: usize,
+    preview_count: usize, // how many pieces next_queue holds
+    script: Vec<PieceType>, // dealt in order instead of the randomizer, if not empty
+    #[serde(default)]
+    loop_script: bool, // from the next game on, like the script
+    #[serde(default)]
+    puzzle: Option<Puzzle>, // every game is played on it until it's left
+    #[serde(default)]
+    pieces_left: Option<usize>, // of the puzzle's, before the game is lost
+    countdown_secs: u64, // 0 starts right away
+    key_bindings: KeyBindings,
+    gamepad_enabled: bool,
+    auto_pause: bool, // pause while the tab is hidden
+    renderer: Renderer,
+    glyphs: bool, // a letter in every piece cell
+    muted: bool,
+    theme: Theme,
+    #[serde(default)]
+    replay: Replay,
+    #[serde(skip, default = "default_rng")]
+    rng: StdRng,
+    #[serde(skip, default = "default_piece_source")]
+    piece_source: Box<dyn PieceSource>,
+    #[serde(skip)]
+    custom_source: bool, // piece_source was set, it outlasts restarts until a randomizer is picked
+    #[serde(skip)]
+    pieces: PieceSet, // saved games go back to the standard pieces
+    #[serde(skip)]
+    history: UndoTree, // for undo and redo in practice mode
+}
+
+/// Supplies the pieces that get spawned, see `State::set_piece_source`.
+pub trait PieceSource {
+    fn next(&mut self) -> PieceType;
+
+    /// The piece a game opens with, never an S, Z or O if the source can deal
+    /// something else without breaking its own rules.
+    fn first(&mut self) -> PieceType {
+        self.next()
+    }
+}
+
+/// Picks pieces uniformly at random, rerolling immediate repeats.
+pub struct RandomPieceSource {
+    rng: StdRng,
+    pieces: Vec<PieceType>,
+    last: Option<PieceType>,
+}
+
+impl RandomPieceSource {
+    pub fn new(rng: StdRng) -> RandomPieceSource {
+        RandomPieceSource::with_pieces(rng, tetrominoes().collect())
+    }
+
+    pub fn with_pieces(rng: StdRng, pieces: Vec<PieceType>) -> RandomPieceSource {
+        RandomPieceSource { rng, pieces, last: None }
+    }
+}
+
+impl PieceSource for RandomPieceSource {
+    fn next(&mut self) -> PieceType {
+        let mut random_piece: PieceType;
+        loop {
+            random_piece = get_random_piece(&mut self.rng, &self.pieces);
+            // a set of one piece can only repeat it
+            if Some(&random_piece) != self.last.as_ref() || self.pieces.len() < 2 {
+                break;
+            }
+        }
+        self.last = Some(random_piece.clone());
+        random_piece
+    }
+
+    fn first(&mut self) -> PieceType {
+        let first_piece = get_random_first_piece(&mut self.rng, &self.pieces);
+        self.last = Some(first_piece.clone());
+        first_piece
+    }
+}
+
+/// Deals the seven tetrominoes in shuffled bags, so each of them shows up
+/// exactly once every seven pieces.
+pub struct BagPieceSource {
+    rng: StdRng,
+    pieces: Vec<PieceType>,
+    bag: Vec<PieceType>,
+}
+
+impl BagPieceSource {
+    pub fn new(rng: StdRng) -> BagPieceSource {
+        BagPieceSource::with_pieces(rng, tetrominoes().collect())
+    }
+
+    pub fn with_pieces(rng: StdRng, pieces: Vec<PieceType>) -> BagPieceSource {
+        BagPieceSource { rng, pieces, bag: Vec::new() }
+    }
+}
+
+impl PieceSource for BagPieceSource {
+    fn next(&mut self) -> PieceType {
+        if self.bag.is_empty() {
+            self.bag = self.pieces.clone();
+            self.bag.shuffle(&mut self.rng);
+        }
+        self.bag.remove(0)
+    }
+
+    // a safe piece is taken out of the first bag ahead of the others, so the
+    // bag still deals each piece exactly once
+    fn first(&mut self) -> PieceType {
+        if self.bag.is_empty() {
+            self.bag = self.pieces.clone();
+            self.bag.shuffle(&mut self.rng);
+        }
+        let index = self.bag.iter().position(|piece| SAFE_FIRST_PIECES.contains(piece));
+        self.bag.remove(index.unwrap_or(0))
+    }
+}
+
+/// Deals a fixed sequence of pieces in order, starting over once it runs out
+/// unless another source takes over from there.
+pub struct ScriptedPieceSource {
+    script: Vec<PieceType>,
+    next: usize,
+    rest: Option<Box<dyn PieceSource>>, // deals after the script instead of a loop
+}
+
+impl ScriptedPieceSource {
+    pub fn new(script: Vec<PieceType>) -> ScriptedPieceSource {
+        ScriptedPieceSource {
+            script,
+            next: 0,
+            rest: None,
+        }
+    }
+
+    /// Deals `script` once, then whatever `rest` deals.
+    pub fn then(script: Vec<PieceType>, rest: Box<dyn PieceSource>) -> ScriptedPieceSource {
+        ScriptedPieceSource {
+            rest: Some(rest),
+            ..ScriptedPieceSource::new(script)
+        }
+    }
+}
+
+impl PieceSource for ScriptedPieceSource {
+    fn next(&mut self) -> PieceType {
+        if self.next == self.script.len() {
+            if let Some(rest) = self.rest.as_mut() {
+                return rest.next();
+            }
+            self.next = 0;
+        }
+        self.next += 1;
+        self.script[self.next - 1].clone()
+    }
+}
+
+// reads a piece sequence like "TSZ IO", refusing letters that aren't pieces
+fn parse_script(text: &str) -> Result<Vec<PieceType>, String> {
+    text.chars()
+        .filter(|letter| !letter.is_whitespace())
+        .map(|letter| {
+            let letter = letter.to_ascii_uppercase().to_string();
+            tetrominoes()
+                .find(|piece| piece.as_ref() == letter)
+                .ok_or_else(|| format!("{} is not a piece", letter))
+        })
+        .collect()
+}
+
+/// A "clear in N pieces" challenge: a board to clear of garbage with the
+/// pieces given, dealt in order. It is won by clearing the last garbage block
+/// and lost once that many pieces are placed without doing so.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Puzzle {
+    pub board: Vec<String>, // rows as `State::with_board` takes them
+    pub pieces: Vec<PieceType>,
+}
+
+impl Puzzle {
+    // reads the board rows, one a line, followed by a line of the pieces, e.g.
+    // "XXXX..XXXX" and "O"
+    fn parse(text: &str) -> Result<Puzzle, String> {
+        let mut lines: Vec<&str> =
+            text.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+        let pieces = parse_script(lines.pop().unwrap_or_default())?;
+        if lines.is_empty() {
+            return Err("A puzzle needs a board above its pieces".into());
+        }
+        Ok(Puzzle {
+            board: lines.iter().map(|line| line.to_string()).collect(),
+            pieces,
+        })
+    }
+
+    fn rows(&self) -> Vec<&str> {
+        self.board.iter().map(String::as_str).collect()
+    }
+}
+
+// which kicks a rotation tries before it fails, replays recorded before
+// there was a choice were played with SRS
+#[derive(Debug, Default, AsRefStr, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RotationSystem {
+    #[default]
+    #[strum(serialize = "SRS")]
+    Srs, // the guideline kick tables, plus nudges off the walls
+    Simple, // in place or a row up, the original feel
+}
+
+impl RotationSystem {
+    fn next(self) -> RotationSystem {
+        match self {
+            RotationSystem::Srs => RotationSystem::Simple,
+            RotationSystem::Simple => RotationSystem::Srs,
+        }
+    }
+}
+
+#[derive(Debug, AsRefStr, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Randomizer {
+    Bag,
+    Uniform,
+}
+
+impl Randomizer {
+    // the source gets its own generator, seeded from the game's, a script
+    // deals first and the randomizer only takes over if it doesn't loop
+    fn piece_source(
+        self,
+        rng: &mut StdRng,
+        pieces: &PieceSet,
+        script: &[PieceType],
+        loop_script: bool,
+    ) -> Box<dyn PieceSource> {
+        let rng = StdRng::seed_from_u64(rng.gen());
+        if !script.is_empty() && loop_script {
+            return Box::new(ScriptedPieceSource::new(script.to_vec()));
+        }
+        let source: Box<dyn PieceSource> = match self {
+            Randomizer::Bag => Box::new(BagPieceSource::with_pieces(rng, pieces.types())),
+            Randomizer::Uniform => Box::new(RandomPieceSource::with_pieces(rng, pieces.types())),
+        };
+        if script.is_empty() {
+            source
+        } else {
+            Box::new(ScriptedPieceSource::then(script.to_vec(), source))
+        }
+    }
+}
+
+#[derive(Debug, AsRefStr, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum GameMode {
+    Marathon,
+    #[strum(serialize = "Sprint 40L")]
+    Sprint, // SPRINT_LINES lines as fast as possible
+    Ultra, // score attack against the clock
+    Cheese, // garbage rises every CHEESE_INTERVAL seconds, for practicing digging
+    Practice, // unscored, locked pieces can be undone
+    Big, // every cell of a piece is a 2x2 block, on a board of half the cells
+}
+
+impl GameMode {
+    fn next(self) -> GameMode {
+        match self {
+            GameMode::Marathon => GameMode::Sprint,
+            GameMode::Sprint => GameMode::Ultra,
+            GameMode::Ultra => GameMode::Cheese,
+            GameMode::Cheese => GameMode::Practice,
+            GameMode::Practice => GameMode::Big,
+            GameMode::Big => GameMode::Marathon,
+        }
+    }
+
+    // board cells across one cell of the stage
+    fn scale(self) -> usize {
+        match self {
+            GameMode::Big => 2,
+            _ => 1,
+        }
+    }
+
+    // lines that win the game, 0 plays on until the stack tops out
+    fn lines_goal(self, marathon_goal: usize) -> Option<usize> {
+        match self {
+            GameMode::Marathon if marathon_goal > 0 => Some(marathon_goal),
+            GameMode::Sprint => Some(SPRINT_LINES),
+            _ => None,
+        }
+    }
+
+    // seconds the game lasts, if it is played against the clock
+    fn time_limit(self) -> Option<u64> {
+        match self {
+            GameMode::Marathon
+            | GameMode::Sprint
+            | GameMode::Cheese
+            | GameMode::Practice
+            | GameMode::Big => None,
+            GameMode::Ultra => Some(ULTRA_SECONDS),
+        }
+    }
+}
+
+fn default_rng() -> StdRng {
+    StdRng::from_entropy()
+}
+
+fn default_piece_source() -> Box<dyn PieceSource> {
+    Randomizer::Bag.piece_source(&mut default_rng(), &PieceSet::default(), &[], false)
+}
+
+#[derive(AsRefStr, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Controls {
+    Left,
+    Right,
+    Down, // gravity
+    #[strum(serialize = "Soft drop")]
+    SoftDrop,
+    #[strum(serialize = "Hard drop")]
+    Bottom,
+    Rotate,
+    #[strum(serialize = "Rotate counterclockwise")]
+    RotateCCW,
+    #[strum(serialize = "Rotate 180")]
+    Rotate180,
+    Hold,
+    Pause,
+    Undo, // practice mode only
+    Redo,
+    #[strum(serialize = "Switch branch")]
+    Branch, // which way redo goes
+}
+
+pub enum Msg {
+    Move(Controls),
+    ToggleBlindMode,
+    Input(Controls),
+    Frame,
+    HideLastClear,
+    HideTrail,
+    CycleHud,
+    ToggleAutoRestart,
+    SetCellGap(String),
+    SetCellRadius(String),
+    SetBackground(String),
+    ToggleSafetyRows,
+    ToggleSafeFirstPiece,
+    ToggleScaledClearDelay,
+    ToggleRandomizer,
+    ToggleGhost,
+    AutoRestart,
+    StartPause,
+    StartInterval,
+    Cancel,
+    Tick,
+    LockTick,
+    KeyDown(String, bool), // key, whether it is an OS key repeat
+    KeyUp(String),
+    PollGamepad,
+    ToggleCanvas,
+    ToggleGlyphs,
+    ToggleMute,
+    CycleTheme,
+    CycleGravity,
+    Restart,
+    CancelRestart,
+    WatchReplay,
+    PlaybackTick,
+    SetScoreName(String),
+    SaveScore,
+    ClearScores,
+    Paint,
+    TouchStart(f64, f64),
+    TouchMove(f64, f64),
+    TouchEnd(f64, f64),
+    ToggleGamepad,
+    ToggleAutoPause,
+    ToggleTrail,
+    VisibilityChange(bool), // whether the tab is now hidden
+    Rebind(Controls),
+    CycleMode,
+    ShiftStart(Controls),
+    ShiftStop(Controls),
+    SoftDropStart,
+    SoftDropStop,
+    Das,
+    Arr,
+    SetStartLevel(String),
+    SetPreviewCount(String),
+    SetLockResetCap(String),
+    CycleRotationSystem,
+    ToggleHoldKeepsRotation,
+    SetMarathonGoal(String),
+    SetCountdown(String),
+    CountdownTick,
+    SetGravityFloor(String),
+    SetDas(String),
+    SetDasCut(String),
+    SetArr(String),
+    SetSoftDropRate(String),
+    ExportReplay,
+    SetReplayText(String),
+    LoadReplay,
+    SetScript(String),
+    ToggleLoopScript,
+    SetPuzzleText(String),
+    StartPuzzle,
+    LeavePuzzle,
+    StartVersus,
+    LeaveVersus,
+    VersusTick,
+}
+
+fn initialize_stage(rows: usize, columns: usize) -> Vec2D {
+    let stage: Vec2D = Vec2D {
+        n_rows: rows,
+        n_cols: columns,
+        data: (0..rows * columns).map(|_| PieceType::E).collect(),
+    };
+    stage
+}
+
+// pieces spawn in the middle, at column 4 of a standard 10 wide stage, with
+// their top row in the hidden buffer row above the visible stage, below any
+// safety rows
+fn spawn_position(n_cols: usize, safety_rows: usize) -> Position {
+    Position {
+        x: n_cols as isize / 2 - 1,
+        y: safety_rows as isize,
+    }
+}
+
+fn initialize_player(
+    piece_source: &mut dyn PieceSource,
+    pieces: &PieceSet,
+    safe_first_piece: bool,
+    n_cols: usize,
+    safety_rows: usize,
+) -> Player {
+    let first_piece = if safe_first_piece {
+        piece_source.first()
+    } else {
+        piece_source.next()
+    };
+    let (random_piece, piece_shape) = pieces.spawn(first_piece);
+    let player: Player = Player {
+        piece_type: random_piece,
+        piece_shape,
+        rotation: 0,
+        position: spawn_position(n_cols, safety_rows),
+        collided: false,
+        last_kick: None,
+        quarter_kick: false,
+        inputs: 0,
+    };
+    player
+}
+
+// a `?seed=<number>` query parameter starts a reproducible game
+fn seed_from_query() -> Option<u64> {
+    let search = window().location()?.search().ok()?;
+    search
+        .trim_start_matches('?')
+        .split('&')
+        .find_map(|param| param.strip_prefix("seed="))
+        .and_then(|seed| seed.parse().ok())
+}
+
+// a `#game=` fragment made by `share_fragment` starts the game it shares
+fn settings_from_location() -> Option<Settings> {
+    let hash = window().location()?.hash().ok()?;
+    if hash.is_empty() {
+        return None;
+    }
+    let settings = settings_from_fragment(&hash);
+    if settings.is_none() {
+        error!("Ignoring a shared game that can't be started: {}", hash);
+    }
+    settings
+}
+
+/// The URL fragment that shares a game started with `settings`: the settings,
+/// seed and any puzzle board included, as percent-encoded JSON.
+pub fn share_fragment(settings: &Settings) -> String {
+    let json = serde_json::to_string(settings).unwrap_or_default();
+    let mut fragment = String::from("#game=");
+    for byte in json.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~".contains(&byte) {
+            fragment.push(byte as char);
+        } else {
+            fragment.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    fragment
+}
+
+/// The settings shared by a `share_fragment`, or `None` for a fragment that
+/// isn't one or asks for a game the page doesn't offer.
+pub fn settings_from_fragment(fragment: &str) -> Option<Settings> {
+    let encoded = fragment.trim_start_matches('#').strip_prefix("game=")?;
+    let json = String::from_utf8(percent_decode(encoded)?).ok()?;
+    let settings: Settings = serde_json::from_str(&json).ok()?;
+    let playable = |puzzle: &Puzzle| State::with_puzzle(settings.clone(), puzzle.clone()).is_ok();
+    let valid = settings.n_rows == STAGE_ROWS
+        && settings.n_cols == STAGE_COLS
+        && settings.safety_rows <= BEGINNER_SAFETY_ROWS
+        && (1..=MAX_START_LEVEL).contains(&settings.start_level)
+        && (1..=MAX_PREVIEW_COUNT).contains(&settings.preview_count)
+        && !settings.custom_pieces
+        && settings.puzzle.iter().all(playable);
+    if valid {
+        Some(settings)
+    } else {
+        None
+    }
+}
+
+fn percent_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut text = text.bytes();
+    while let Some(byte) = text.next() {
+        if byte == b'%' {
+            let hex = [text.next()?, text.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    Some(bytes)
+}
+
+fn initialize_queue(piece_source: &mut dyn PieceSource, len: usize) -> Vec<PieceType> {
+    (0..len).map(|_| piece_source.next()).collect()
+}
+
+// text read out by screen readers in blind mode, e.g. "Locked. T piece, column 5"
+fn announcement(event: &str, player: &Player) -> String {
+    let Vec2D { n_rows, n_cols, .. } = player.piece_shape.clone();
+    let first_col = (0..n_cols)
+        .find(|&col| (0..n_rows).any(|row| player.piece_shape.get(row, col) != PieceType::E.as_ref()))
+        .unwrap_or(0) as isize;
+    format!(
+        "{}. {} piece, column {}",
+        event,
+        player.piece_type.as_ref(),
+        player.position.x + first_col + 1
+    )
+}
+
+fn initialize_game_status(start_level: usize) -> GameStatus {
+    let game: GameStatus = GameStatus {
+        level: start_level,
+        start_level,
+        rows_cleared: 0,
+        score: 0,
+        combo: -1,
+        back_to_back: false,
+        finesse_faults: 0,
+        game_won: false,
+        game_over: false,
+    };
+    game
+}
+
+fn tetrominoes() -> impl Iterator<Item = PieceType> {
+    PieceType::iter().filter(|piece| *piece != PieceType::E && *piece != PieceType::G)
+}
+
+fn initialize_droughts() -> HashMap<PieceType, usize> {
+    tetrominoes().map(|piece| (piece, 0)).collect()
+}
+
+fn get_random_piece(rng: &mut StdRng, pieces: &[PieceType]) -> PieceType {
+    let num = rng.gen_range(0, pieces.len());
+    info!("random number: {}", num);
+    pieces[num].clone()
+}
+
+// guideline games never open with S, Z or O, which would force an overhang or
+// gap right away, unless the piece set has nothing else
+const SAFE_FIRST_PIECES: [PieceType; 4] = [PieceType::I, PieceType::J, PieceType::L, PieceType::T];
+
+fn get_random_first_piece(rng: &mut StdRng, pieces: &[PieceType]) -> PieceType {
+    let safe: Vec<PieceType> = SAFE_FIRST_PIECES
+        .iter()
+        .filter(|piece| pieces.contains(piece))
+        .cloned()
+        .collect();
+    if safe.is_empty() {
+        get_random_piece(rng, pieces)
+    } else {
+        get_random_piece(rng, &safe)
+    }
+}
+
+// keeps the first occurrence of each control, dropping the repeats that piled
+// up within a single frame
+fn coalesce_inputs(inputs: Vec<Controls>) -> Vec<Controls> {
+    let mut controls: Vec<Controls> = Vec::new();
+    for input in inputs {
+        if !controls.contains(&input) {
+            controls.push(input);
+        }
+    }
+    controls
+}
+
+// plays `direction` through `step` until the piece stops moving, what a
+// charged DAS does with an ARR of 0
+fn shift_to_wall(direction: Controls, mut step: impl FnMut(ReplayEvent) -> StepOutcome) {
+    while matches!(
+        step(ReplayEvent::Step(direction.clone())),
+        StepOutcome::Moved | StepOutcome::LockReset
+    ) {}
+}
+
+fn classify_clear(rows_cleared: usize, spin: Option<TSpin>) -> Option<ClearKind> {
+    match (spin, rows_cleared) {
+        (_, 0) => None,
+        (None, 1) => Some(ClearKind::Single),
+        (None, 2) => Some(ClearKind::Double),
+        (None, 3) => Some(ClearKind::Triple),
+        (None, _) => Some(ClearKind::Tetris),
+        (Some(TSpin::Mini), 1) => Some(ClearKind::MiniTSpinSingle),
+        (Some(TSpin::Mini), _) => Some(ClearKind::MiniTSpinDouble),
+        (Some(TSpin::Full), 1) => Some(ClearKind::TSpinSingle),
+        (Some(TSpin::Full), 2) => Some(ClearKind::TSpinDouble),
+        (Some(TSpin::Full), _) => Some(ClearKind::TSpinTriple),
+    }
+}
+
+// a flat delay by default, otherwise a Tetris is held longer than a single
+fn last_clear_duration(rows_cleared: usize, scaled: bool) -> u64 {
+    if scaled && rows_cleared > 1 {
+        LAST_CLEAR_DURATION + LAST_CLEAR_STEP * (rows_cleared as u64 - 1)
+    } else {
+        LAST_CLEAR_DURATION
+    }
+}
+
+fn cells_per_second(duration_ms: f64) -> f64 {
+    1000.0 / duration_ms
+}
+
+const NES_FRAME: f64 = 1000.0 / 60.0988; // ms, NTSC
+
+// how fast gravity gets with the level
+#[derive(Debug, AsRefStr, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum GravityProfile {
+    Guideline,
+    #[strum(serialize = "NES")]
+    Nes, // the NES frame table, and a palette that changes every level
+}
+
+impl GravityProfile {
+    fn next(self) -> GravityProfile {
+        match self {
+            GravityProfile::Guideline => GravityProfile::Nes,
+            GravityProfile::Nes => GravityProfile::Guideline,
+        }
+    }
+
+    // ms per row, only the guideline curve is held back by `floor`
+    fn duration(self, level: usize, floor: f64) -> f64 {
+        match self {
+            GravityProfile::Guideline => get_duration(level, floor),
+            GravityProfile::Nes => nes_frames(level) as f64 * NES_FRAME,
+        }
+    }
+}
+
+// frames per row on the NES, whose levels count from 0: 48 frames at level 1
+// here, 6 at 10 and down to a frame a row, the killscreen speed, from 30 on
+fn nes_frames(level: usize) -> usize {
+    match level.max(1) - 1 {
+        level @ 0..=8 => 48 - 5 * level,
+        9 => 6,
+        10..=12 => 5,
+        13..=15 => 4,
+        16..=18 => 3,
+        19..=28 => 2,
+        _ => 1,
+    }
+}
+
+// milliseconds per row of gravity, following the guideline curve
+// (0.8 - (level - 1) * 0.007) ^ (level - 1) seconds: 1000ms at level 1, 793ms
+// at 2, 355ms at 5 and 64ms at 10, until it plateaus at `floor`. Levels past
+// the point where the curve turns around don't speed it up any further.
+fn get_duration(level: usize, floor: f64) -> f64 {
+    let level = level.clamp(1, 20) as f64;
+    let seconds = (0.8 - (level - 1.0) * 0.007).powf(level - 1.0);
+    (seconds * 1000.0).max(floor.max(MIN_DURATION))
+}
+
+impl Component for Model {
+    type Message = Msg;
+    type Properties = ();
+
+    fn mounted(&mut self) -> ShouldRender {
+        self.request_paint();
+        false
+    }
+
+    fn create(_: Self::Properties, link: ComponentLink<Self>) -> Self {
+        let storage = StorageService::new(Area::Local);
+
+        let interval = IntervalService::new();
+
+        // a seed or a shared game in the URL asks for a fresh, reproducible
+        // game instead
+        let seed = seed_from_query();
+        let shared = settings_from_location();
+        let restored = {
+            if let (None, None, Json(Ok(restored_state))) = (seed, &shared, storage.restore(KEY)) {
+                State::restored(restored_state)
+            } else {
+                None
+            }
+        };
+
+        let hud = {
+            if let Json(Ok(hud)) = storage.restore(HUD_KEY) {
+                hud
+            } else {
+                HudMode::Full
+            }
+        };
+
+        let appearance = {
+            if let Json(Ok(appearance)) = storage.restore(APPEARANCE_KEY) {
+                appearance
+            } else {
+                Appearance::default()
+            }
+        };
+
+        // a missing or unreadable leaderboard starts empty
+        let leaderboard = {
+            if let Json(Ok(leaderboard)) = storage.restore(LEADERBOARD_KEY) {
+                leaderboard
+            } else {
+                Leaderboard::default()
+            }
+        };
+        let sprint_times = {
+            if let Json(Ok(sprint_times)) = storage.restore(SPRINT_TIMES_KEY) {
+                sprint_times
+            } else {
+                SprintTimes::default()
+            }
+        };
+
+        let start_level = {
+            if let Json(Ok(start_level)) = storage.restore(START_LEVEL_KEY) {
+                start_level
+            } else {
+                1
+            }
+        };
+
+        let preview_count = {
+            if let Json(Ok(preview_count)) = storage.restore(PREVIEW_COUNT_KEY) {
+                preview_count
+            } else {
+                PREVIEW_COUNT
+            }
+        };
+
+        let marathon_goal = {
+            if let Json(Ok(marathon_goal)) = storage.restore(MARATHON_GOAL_KEY) {
+                marathon_goal
+            } else {
+                MARATHON_GOAL
+            }
+        };
+
+        let lock_reset_cap = {
+            if let Json(Ok(lock_reset_cap)) = storage.restore(LOCK_RESET_CAP_KEY) {
+                lock_reset_cap
+            } else {
+                LOCK_RESET_CAP
+            }
+        };
+
+        let gravity_floor_ms = {
+            if let Json(Ok(gravity_floor_ms)) = storage.restore(GRAVITY_FLOOR_KEY) {
+                gravity_floor_ms
+            } else {
+                GRAVITY_FLOOR_DEFAULT
+            }
+        };
+
+        let soft_drop_ms = {
+            if let Json(Ok(soft_drop_ms)) = storage.restore(SOFT_DROP_KEY) {
+                soft_drop_ms
+            } else {
+                0
+            }
+        };
+
+        let rotation_system = {
+            if let Json(Ok(rotation_system)) = storage.restore(ROTATION_SYSTEM_KEY) {
+                rotation_system
+            } else {
+                RotationSystem::default()
+            }
+        };
+
+        let hold_keeps_rotation = {
+            if let Json(Ok(hold_keeps_rotation)) = storage.restore(HOLD_ROTATION_KEY) {
+                hold_keeps_rotation
+            } else {
+                false
+            }
+        };
+
+        let mut game = match (restored, shared) {
+            (Some(state), _) => Game { state },
+            (None, Some(settings)) => Game::new(settings),
+            (None, None) => Game::new(Settings {
+                seed,
+                start_level,
+                preview_count,
+                lock_reset_cap,
+                gravity_floor_ms,
+                soft_drop_ms,
+                rotation_system,
+                hold_keeps_rotation,
+                marathon_goal,
+                ..Settings::default()
+            }),
+        };
+        game.state.hud = hud;
+        game.state.appearance = appearance;
+        if let Json(Ok(theme)) = storage.restore(THEME_KEY) {
+            game.state.theme = theme;
+        }
+        if let Json(Ok(profile)) = storage.restore(GRAVITY_PROFILE_KEY) {
+            game.state.gravity_profile = profile;
+        }
+        if let Json(Ok(muted)) = storage.restore(MUTED_KEY) {
+            game.state.muted = muted;
+        }
+        if let Json(Ok(ultra_best)) = storage.restore(ULTRA_BEST_KEY) {
+            game.state.ultra_best = ultra_best;
+        }
+        if let Json(Ok(key_bindings)) = storage.restore(KEY_BINDINGS_KEY) {
+            game.state.key_bindings = key_bindings;
+        }
+        if let Json(Ok(countdown_secs)) = storage.restore(COUNTDOWN_KEY) {
+            game.state.countdown_secs = countdown_secs;
+        }
+        if let Json(Ok(das_ms)) = storage.restore(DAS_KEY) {
+            game.state.das_ms = das_ms;
+        }
+        if let Json(Ok(das_cut_ms)) = storage.restore(DAS_CUT_KEY) {
+            game.state.das_cut_ms = das_cut_ms;
+        }
+        if let Json(Ok(arr_ms)) = storage.restore(ARR_KEY) {
+            game.state.arr_ms = arr_ms;
+        }
+
+        let mut listeners = Vec::new();
+        let mut link_clone = link.clone();
+        listeners.push(document().add_event_listener(move |event: KeyDownEvent| {
+            if !in_text_field(event.target()) {
+                link_clone.send_message(Msg::KeyDown(event.key(), event.repeat()));
+            }
+        }));
+
+        let mut link_clone = link.clone();
+        listeners.push(document().add_event_listener(move |event: KeyUpEvent| {
+            if !in_text_field(event.target()) {
+                link_clone.send_message(Msg::KeyUp(event.key()));
+            }
+        }));
+
+        // switching tabs pauses the game, see `Msg::VisibilityChange`
+        let mut link_clone = link.clone();
+        let visibility_change = move |hidden: bool| {
+            link_clone.send_message(Msg::VisibilityChange(hidden));
+        };
+        let remove_visibility_listener = js! {
+            var callback = @{visibility_change};
+            var listener = function() {
+                callback(document.hidden);
+            };
+            document.addEventListener("visibilitychange", listener);
+            return function() {
+                document.removeEventListener("visibilitychange", listener);
+                callback.drop();
+            };
+        };
+
+        // swipes and taps on the board
+        let mut link_clone = link.clone();
+        listeners.push(document().add_event_listener(move |event: TouchStart| {
+            if on_board(event.target()) {
+                let (x, y) = touch_point(event.changed_touches());
+                link_clone.send_message(Msg::TouchStart(x, y));
+            }
+        }));
+
+        let mut link_clone = link.clone();
+        listeners.push(document().add_event_listener(move |event: TouchMove| {
+            if on_board(event.target()) {
+                // the board is played on, not scrolled
+                event.prevent_default();
+                let (x, y) = touch_point(event.changed_touches());
+                link_clone.send_message(Msg::TouchMove(x, y));
+            }
+        }));
+
+        let mut link_clone = link.clone();
+        listeners.push(document().add_event_listener(move |event: TouchEnd| {
+            let (x, y) = touch_point(event.changed_touches());
+            link_clone.send_message(Msg::TouchEnd(x, y));
+        }));
+
+        let mut model = Model {
+            link: link.clone(),
+            storage,
+            interval,
+            jobs: Jobs::default(),
+            callback_tick: link.callback(|_| Msg::Tick),
+            timeout: TimeoutService::new(),
+            playback: None,
+            countdown: None,
+            render: RenderService::new(),
+            frame_job: None,
+            callback_frame: link.callback(|_| Msg::Frame),
+            inputs: Vec::new(),
+            last_tick_at: Date::now(),
+            gravity_acc: 0.0,
+            replay_text: String::new(),
+            replay_error: None,
+            script_text: String::new(),
+            script_error: None,
+            puzzle_text: String::new(),
+            puzzle_error: None,
+            rebinding: None,
+            binding_error: None,
+            score_error: None,
+            gamepad_buttons: Vec::new(),
+            swipe: None,
+            canvas: NodeRef::default(),
+            paint_job: None,
+            leaderboard,
+            sprint_times,
+            new_score: None,
+            auto_paused: false,
+            listeners,
+            remove_visibility_listener,
+            game,
+            versus: None,
+        };
+        if model.game.state.gamepad_enabled {
+            model.jobs.gamepad = Some(model.poll_gamepad());
+        }
+        model
+    }
+
+    fn update(&mut self, msg: Self::Message) -> ShouldRender {
+        // anything the player does besides a second Restart takes the first back
+        let background = matches!(
+            msg,
+            Msg::Tick
+                | Msg::LockTick
+                | Msg::CountdownTick
+                | Msg::Frame
+                | Msg::Paint
+                | Msg::PollGamepad
+                | Msg::Das
+                | Msg::Arr
+                | Msg::HideLastClear
+                | Msg::HideTrail
+                | Msg::AutoRestart
+                | Msg::VisibilityChange(_)
+                | Msg::PlaybackTick
+                | Msg::VersusTick
+        );
+        if !background && !matches!(msg, Msg::Restart) {
+            self.jobs.confirm_restart = None;
+        }
+        // while a game is watched again any input only skips to its end
+        if !background && self.playback.is_some() {
+            self.finish_playback();
+            return true;
+        }
+        match msg {
+            Msg::StartPause if self.versus.is_some() => return false,
+            Msg::StartPause => {
+                if self.jobs.gravity.is_none()
+                    && !self.game.state.paused
+                    && self.countdown.is_none()
+                {
+                    if self.game.is_over() {
+                        self.game.state.initialize_game();
+                    }
+                    if self.game.state.countdown_secs > 0 {
+                        self.start_countdown();
+                    } else {
+                        self.start_game();
+                    }
+                } else {
+                    self.toggle_pause();
+                }
+            }
+            Msg::CountdownTick => {
+                let left = self.countdown.unwrap_or(1) - 1;
+                if left > 0 {
+                    self.countdown = Some(left);
+                } else {
+                    self.countdown = None;
+                    self.jobs.countdown = None;
+                    if self.game.state.paused {
+                        self.link.send_message(Msg::Move(Controls::Pause));
+                    } else {
+                        self.start_game();
+                    }
+                }
+            }
+            Msg::StartInterval => {
+                self.gravity_acc = 0.0;
+                self.start_gravity();
+            }
+            Msg::Cancel => {
+                if let Some(mut task) = self.jobs.gravity.take() {
+                    task.cancel();
+                }
+                info!("Canceled");
+                if self.jobs.gravity.is_none() {
+                    info!("Job still exists!");
+                }
+            }
+            Msg::Tick => {
+                let now = Date::now();
+                self.gravity_acc += now - self.last_tick_at;
+                self.last_tick_at = now;
+                // the level, and with it the duration, can change with each row
+                let mut fell = false;
+                while self.jobs.gravity.is_some()
+                    && self.game.state.take_gravity_row(&mut self.gravity_acc)
+                {
+                    info!("Tick..");
+                    let outcome = self.play(ReplayEvent::Tick);
+                    self.apply(outcome);
+                    fell = true;
+                }
+                let counted = self.count_seconds(now);
+                // most ticks only add to the accumulator, nothing to save or draw
+                if !fell && !counted {
+                    return false;
+                }
+            }
+            Msg::Move(control) => {
+                let outcome = self.play(ReplayEvent::Step(control));
+                self.apply(outcome);
+            }
+            Msg::ShiftStart(direction) => {
+                self.game.state.held_directions.retain(|held| *held != direction);
+                self.game.state.held_directions.push(direction.clone());
+                self.link.send_message(Msg::Input(direction));
+                self.start_das();
+            }
+            Msg::ShiftStop(direction) => {
+                self.game.state.held_directions.retain(|held| *held != direction);
+                // the other direction takes over if it is still held
+                if self.game.state.held_directions.is_empty() {
+                    self.jobs.shift = None;
+                } else {
+                    self.start_das();
+                }
+            }
+            Msg::SoftDropStart => {
+                if self.game.state.soft_dropping {
+                    return false;
+                }
+                self.game.state.soft_dropping = true;
+                self.link.send_message(Msg::Input(Controls::SoftDrop));
+                let handle = self.interval.spawn(
+                    Duration::from_millis(self.game.state.soft_drop_interval()),
+                    self.link.callback(|_| Msg::Input(Controls::SoftDrop)),
+                );
+                self.jobs.soft_drop = Some(Box::new(handle));
+            }
+            Msg::SoftDropStop => {
+                self.game.state.soft_dropping = false;
+                self.jobs.soft_drop = None;
+            }
+            Msg::Das => {
+                if self.game.state.arr_ms == 0 {
+                    self.jobs.shift = None;
+                    self.shift_to_wall();
+                } else {
+                    let handle = self.interval.spawn(
+                        Duration::from_millis(self.game.state.arr_ms),
+                        self.link.callback(|_| Msg::Arr),
+                    );
+                    self.jobs.shift = Some(Box::new(handle));
+                }
+            }
+            Msg::Arr => {
+                if let Some(direction) = self.game.state.held_directions.last() {
+                    self.link.send_message(Msg::Input(direction.clone()));
+                }
+            }
+            Msg::SetPreviewCount(value) => {
+                if let Ok(preview_count) = value.parse::<usize>() {
+                    let preview_count = preview_count.clamp(1, MAX_PREVIEW_COUNT);
+                    // the queue deals from the piece source, so the replay needs it too
+                    let outcome = self.play(ReplayEvent::PreviewCount(preview_count));
+                    self.apply(outcome);
+                    self.storage.store(PREVIEW_COUNT_KEY, Json(&preview_count));
+                }
+            }
+            Msg::SetMarathonGoal(value) => {
+                if let Ok(marathon_goal) = value.parse::<usize>() {
+                    self.game.state.marathon_goal = marathon_goal;
+                    self.storage.store(MARATHON_GOAL_KEY, Json(&marathon_goal));
+                }
+            }
+            Msg::SetLockResetCap(value) => {
+                if let Ok(lock_reset_cap) = value.parse::<usize>() {
+                    let lock_reset_cap = lock_reset_cap.min(MAX_LOCK_RESET_CAP);
+                    let outcome = self.play(ReplayEvent::LockResetCap(lock_reset_cap));
+                    self.apply(outcome);
+                    self.storage.store(LOCK_RESET_CAP_KEY, Json(&lock_reset_cap));
+                }
+            }
+            Msg::CycleRotationSystem => {
+                let rotation_system = self.game.state.rotation_system.next();
+                let outcome = self.play(ReplayEvent::RotationSystem(rotation_system));
+                self.apply(outcome);
+                self.storage.store(ROTATION_SYSTEM_KEY, Json(&rotation_system));
+            }
+            Msg::ToggleHoldKeepsRotation => {
+                let hold_keeps_rotation = !self.game.state.hold_keeps_rotation;
+                let outcome = self.play(ReplayEvent::HoldKeepsRotation(hold_keeps_rotation));
+                self.apply(outcome);
+                self.storage.store(HOLD_ROTATION_KEY, Json(&hold_keeps_rotation));
+            }
+            Msg::SetCountdown(value) => {
+                if let Ok(countdown_secs) = value.parse::<u64>() {
+                    let countdown_secs = countdown_secs.min(MAX_COUNTDOWN);
+                    self.game.state.countdown_secs = countdown_secs;
+                    self.storage.store(COUNTDOWN_KEY, Json(&countdown_secs));
+                }
+            }
+            Msg::SetStartLevel(value) => {
+                if let Ok(start_level) = value.parse::<usize>() {
+                    let start_level = start_level.clamp(1, MAX_START_LEVEL);
+                    // it can change the level of a game that hasn't scored yet
+                    let outcome = self.play(ReplayEvent::StartLevel(start_level));
+                    self.apply(outcome);
+                    self.storage.store(START_LEVEL_KEY, Json(&start_level));
+                }
+            }
+            Msg::SetGravityFloor(value) => {
+                if let Ok(gravity_floor_ms) = value.parse::<u64>() {
+                    let gravity_floor_ms = gravity_floor_ms.max(MIN_DURATION as u64);
+                    let outcome = self.play(ReplayEvent::GravityFloor(gravity_floor_ms));
+                    self.apply(outcome);
+                    self.storage.store(GRAVITY_FLOOR_KEY, Json(&gravity_floor_ms));
+                }
+            }
+            Msg::SetDas(value) => {
+                if let Ok(das_ms) = value.parse() {
+                    self.game.state.das_ms = das_ms;
+                    self.storage.store(DAS_KEY, Json(&das_ms));
+                }
+            }
+            Msg::SetDasCut(value) => {
+                if let Ok(das_cut_ms) = value.parse() {
+                    self.game.state.das_cut_ms = das_cut_ms;
+                    self.storage.store(DAS_CUT_KEY, Json(&das_cut_ms));
+                }
+            }
+            Msg::SetArr(value) => {
+                if let Ok(arr_ms) = value.parse() {
+                    self.game.state.arr_ms = arr_ms;
+                    self.storage.store(ARR_KEY, Json(&arr_ms));
+                }
+            }
+            Msg::SetSoftDropRate(value) => {
+                if let Ok(soft_drop_ms) = value.parse() {
+                    let outcome = self.play(ReplayEvent::SoftDropRate(soft_drop_ms));
+                    self.apply(outcome);
+                    self.storage.store(SOFT_DROP_KEY, Json(&soft_drop_ms));
+                }
+            }
+            Msg::CycleMode => {
+                self.game.state.next_mode = self.game.state.next_mode.next();
+            }
+            Msg::LockTick => {
+                self.jobs.lock = None;
+                let outcome = self.play(ReplayEvent::Lock);
+                self.apply(outcome);
+            }
+            Msg::ExportReplay => {
+                self.replay_text = self.game.state.export_replay();
+                self.replay_error = None;
+            }
+            Msg::SetReplayText(value) => {
+                self.replay_text = value;
+            }
+            Msg::LoadReplay => {
+                let replay_text = self.replay_text.clone();
+                self.replay_error = self.load_replay(&replay_text).err();
+            }
+            Msg::SetScript(value) => {
+                match parse_script(&value) {
+                    Ok(script) => {
+                        self.game.state.script = script;
+                        self.script_error = None;
+                    }
+                    Err(error) => self.script_error = Some(error),
+                }
+                self.script_text = value;
+            }
+            Msg::ToggleLoopScript => {
+                self.game.state.loop_script = !self.game.state.loop_script;
+            }
+            Msg::SetPuzzleText(value) => {
+                self.puzzle_text = value;
+            }
+            Msg::StartPuzzle => {
+                let puzzle_text = self.puzzle_text.clone();
+                self.puzzle_error = self.start_puzzle(&puzzle_text).err();
+            }
+            Msg::LeavePuzzle => {
+                self.game.state.puzzle = None;
+            }
+            Msg::StartVersus => self.start_versus(),
+            Msg::LeaveVersus => {
+                self.versus = None;
+                self.jobs.versus = None;
+            }
+            Msg::VersusTick => {
+                let now = Date::now();
+                let elapsed = now - self.last_tick_at;
+                self.last_tick_at = now;
+                let versus = match self.versus.as_mut() {
+                    Some(versus) => versus,
+                    None => return false,
+                };
+                if !versus.fall(elapsed) {
+                    return false;
+                }
+                if versus.winner().is_some() {
+                    self.jobs.versus = None;
+                }
+            }
+            Msg::Input(control) => {
+                if self.countdown.is_some() {
+                    return false;
+                }
+                // inputs are buffered and handled once per animation frame,
+                // so OS key repeat can't flood the message queue
+                self.inputs.push(control);
+                if self.frame_job.is_none() {
+                    let handle = self
+                        .render
+                        .request_animation_frame(self.callback_frame.clone());
+                    self.frame_job = Some(handle);
+                }
+                return false;
+            }
+            Msg::Frame => {
+                self.frame_job = None;
+                let controls = coalesce_inputs(self.inputs.drain(..).collect());
+                // inputs made while paused are dropped, so gravity stays stopped
+                if !controls.is_empty() && !self.game.state.paused {
+                    let messages: Vec<Msg> = controls.into_iter().map(Msg::Move).collect();
+                    self.link.send_message_batch(messages);
+                }
+                return false;
+            }
+            Msg::SetCellGap(value) => {
+                if let Ok(cell_gap) = value.parse() {
+                    self.game.state.appearance.cell_gap = cell_gap;
+                    self.storage.store(APPEARANCE_KEY, Json(&self.game.state.appearance));
+                }
+            }
+            Msg::SetCellRadius(value) => {
+                if let Ok(cell_radius) = value.parse() {
+                    self.game.state.appearance.cell_radius = cell_radius;
+                    self.storage.store(APPEARANCE_KEY, Json(&self.game.state.appearance));
+                }
+            }
+            Msg::SetBackground(value) => {
+                self.game.state.appearance.background = value;
+                self.storage.store(APPEARANCE_KEY, Json(&self.game.state.appearance));
+            }
+            Msg::ToggleSafetyRows => {
+                self.game.state.safety_rows = if self.game.state.safety_rows == 0 {
+                    BEGINNER_SAFETY_ROWS
+                } else {
+                    0
+                };
+            }
+            Msg::ToggleSafeFirstPiece => {
+                self.game.state.safe_first_piece = !self.game.state.safe_first_piece;
+            }
+            Msg::ToggleScaledClearDelay => {
+                self.game.state.scaled_clear_delay = !self.game.state.scaled_clear_delay;
+            }
+            Msg::ToggleRandomizer => {
+                let randomizer = match self.game.state.randomizer {
+                    Randomizer::Bag => Randomizer::Uniform,
+                    Randomizer::Uniform => Randomizer::Bag,
+                };
+                let outcome = self.play(ReplayEvent::Randomizer(randomizer));
+                self.apply(outcome);
+            }
+            Msg::Restart => {
+                // a game in progress takes a second click to throw away
+                let in_progress = !self.game.is_over()
+                    && (self.jobs.gravity.is_some() || self.game.state.paused);
+                if in_progress && self.jobs.confirm_restart.is_none() {
+                    let handle = self.timeout.spawn(
+                        Duration::from_millis(CONFIRM_RESTART_DELAY),
+                        self.link.callback(|_| Msg::CancelRestart),
+                    );
+                    self.jobs.confirm_restart = Some(Box::new(handle));
+                    return true;
+                }
+                self.jobs.confirm_restart = None;
+                self.stop_jobs();
+                self.game.state.initialize_game();
+                self.game.state.played.start(Date::now());
+                self.link.send_message(Msg::StartInterval);
+            }
+            Msg::CancelRestart => {} // taken back above, like for any other input
+            Msg::WatchReplay => {
+                if !self.game.is_over() || !self.game.state.replay.can_replay() {
+                    return false;
+                }
+                let (playback, mut game) = Playback::new(self.game.state.replay.clone());
+                game.state.copy_preferences(&self.game.state);
+                self.stop_jobs();
+                self.game = game;
+                self.playback = Some(playback);
+                self.game.state.played.start(Date::now());
+                let callback = self.link.callback(|_| Msg::PlaybackTick);
+                let handle = self.interval.spawn(Duration::from_millis(GRAVITY_STEP), callback);
+                self.jobs.playback = Some(Box::new(handle));
+            }
+            Msg::PlaybackTick => {
+                let at = self.game.state.played.at(Date::now());
+                let played = match self.playback.as_mut() {
+                    Some(playback) => playback.advance(&mut self.game, at),
+                    None => false,
+                };
+                if self.playback.as_ref().is_some_and(Playback::is_finished) {
+                    self.finish_playback();
+                }
+                if !played {
+                    return false;
+                }
+                self.request_paint();
+                return true;
+            }
+            Msg::SetScoreName(name) => {
+                if let Some(score) = self.new_score.as_mut() {
+                    score.name = Some(name).filter(|name| !name.is_empty());
+                }
+            }
+            Msg::SaveScore => {
+                if let Some(score) = self.new_score.take() {
+                    self.score_error = self.leaderboard.submit(score).err();
+                    self.storage.store(LEADERBOARD_KEY, Json(&self.leaderboard));
+                }
+            }
+            Msg::ClearScores => {
+                self.leaderboard = Leaderboard::default();
+                self.storage.store(LEADERBOARD_KEY, Json(&self.leaderboard));
+                self.sprint_times = SprintTimes::default();
+                self.storage.store(SPRINT_TIMES_KEY, Json(&self.sprint_times));
+            }
+            Msg::ToggleCanvas => {
+                self.game.state.renderer = match self.game.state.renderer {
+                    Renderer::Table => Renderer::Canvas,
+                    Renderer::Canvas => Renderer::Table,
+                };
+            }
+            Msg::CycleGravity => {
+                self.game.state.gravity_profile = self.game.state.gravity_profile.next();
+                self.storage.store(GRAVITY_PROFILE_KEY, Json(&self.game.state.gravity_profile));
+            }
+            Msg::CycleTheme => {
+                self.game.state.theme = self.game.state.theme.next();
+                self.storage.store(THEME_KEY, Json(&self.game.state.theme));
+            }
+            Msg::ToggleGlyphs => {
+                self.game.state.glyphs = !self.game.state.glyphs;
+            }
+            Msg::ToggleMute => {
+                self.game.state.muted = !self.game.state.muted;
+                self.storage.store(MUTED_KEY, Json(&self.game.state.muted));
+            }
+            Msg::Paint => {
+                self.paint_job = None;
+                self.paint();
+                return false;
+            }
+            Msg::ToggleGhost => {
+                self.game.state.ghost_enabled = !self.game.state.ghost_enabled;
+            }
+            Msg::ToggleAutoRestart => {
+                self.game.state.auto_restart = !self.game.state.auto_restart;
+            }
+            Msg::AutoRestart => {
+                self.jobs.restart = None;
+                // the player may have restarted by hand in the meantime
+                if self.game.state.restarts_itself(self.jobs.gravity.is_some()) {
+                    self.link.send_message(Msg::StartPause);
+                }
+            }
+            Msg::KeyDown(key, repeat) => {
+                if self.versus.is_some() {
+                    return self.press_versus(&key);
+                }
+                if let Some(control) = self.rebinding.take() {
+                    let key_bindings = &mut self.game.state.key_bindings;
+                    self.binding_error = key_bindings.bind(&control, key.clone()).err().map(|other| {
+                        format!("{} is already used for {}", key_name(&key), other.as_ref())
+                    });
+                    self.storage.store(KEY_BINDINGS_KEY, Json(&self.game.state.key_bindings));
+                    return true;
+                }
+                match self.game.state.key_bindings.control(&key) {
+                    // held keys repeat through DAS/ARR and the soft drop timer,
+                    // not the OS key repeat
+                    Some(Controls::Left) | Some(Controls::Right) | Some(Controls::SoftDrop) if repeat => {}
+                    Some(control) => self.press(control),
+                    None if key == "Enter" => self.link.send_message(Msg::StartPause),
+                    None if key == "h" => self.link.send_message(Msg::CycleHud),
+                    None => {}
+                }
+            }
+            Msg::KeyUp(key) => {
+                if let Some(control) = self.game.state.key_bindings.control(&key) {
+                    self.release(control);
+                }
+            }
+            Msg::PollGamepad => {
+                let buttons = gamepad_buttons();
+                // a button only counts when it goes down or up, held directions
+                // repeat through DAS/ARR like held keys
+                for button in 0..buttons.len().max(self.gamepad_buttons.len()) {
+                    let pressed = buttons.get(button).cloned().unwrap_or(false);
+                    let was_pressed = self.gamepad_buttons.get(button).cloned().unwrap_or(false);
+                    if pressed == was_pressed {
+                        continue;
+                    }
+                    match gamepad_control(button) {
+                        Some(control) if pressed => self.press(control),
+                        Some(control) => self.release(control),
+                        None if pressed && button == GAMEPAD_START => {
+                            self.link.send_message(Msg::StartPause);
+                        }
+                        None => {}
+                    }
+                }
+                self.gamepad_buttons = buttons;
+                return false;
+            }
+            Msg::TouchStart(x, y) => {
+                self.swipe = Some(Swipe {
+                    start: (x, y),
+                    anchor: (x, y),
+                    started_at: Date::now(),
+                    moved: false,
+                });
+                return false;
+            }
+            Msg::TouchMove(x, y) => {
+                if let Some(swipe) = self.swipe.as_mut() {
+                    let (dx, dy) = (x - swipe.anchor.0, y - swipe.anchor.1);
+                    let control = if dx.abs() >= SWIPE_DISTANCE && dx.abs() > dy.abs() {
+                        Some(if dx > 0.0 { Controls::Right } else { Controls::Left })
+                    } else if dy >= SWIPE_DISTANCE {
+                        Some(Controls::SoftDrop)
+                    } else {
+                        None
+                    };
+                    if let Some(control) = control {
+                        swipe.anchor = (x, y);
+                        swipe.moved = true;
+                        self.link.send_message(Msg::Input(control));
+                    }
+                }
+                return false;
+            }
+            Msg::TouchEnd(x, y) => {
+                if let Some(swipe) = self.swipe.take() {
+                    let (dx, dy) = (x - swipe.start.0, y - swipe.start.1);
+                    let flick = Date::now() - swipe.started_at < FLICK_DURATION;
+                    if flick && dy >= SWIPE_DISTANCE && dy > dx.abs() {
+                        self.link.send_message(Msg::Input(Controls::Bottom));
+                    } else if -dy >= SWIPE_DISTANCE && -dy > dx.abs() {
+                        self.link.send_message(Msg::Input(Controls::Rotate));
+                    } else if !swipe.moved && dx.abs() < SWIPE_DISTANCE && dy.abs() < SWIPE_DISTANCE {
+                        // a tap
+                        self.link.send_message(Msg::Input(Controls::Rotate));
+                    }
+                }
+                return false;
+            }
+            Msg::VisibilityChange(hidden) => {
+                let running = self.jobs.gravity.is_some() && !self.game.state.paused;
+                if hidden && self.game.state.auto_pause && (running || self.countdown.is_some()) {
+                    self.auto_paused = running;
+                    self.toggle_pause();
+                } else if !hidden && self.auto_paused {
+                    // a pause the player made themselves is left alone
+                    self.auto_paused = false;
+                    if self.game.state.paused {
+                        self.toggle_pause();
+                    }
+                }
+            }
+            Msg::ToggleTrail => {
+                self.game.state.trail_enabled = !self.game.state.trail_enabled;
+            }
+            Msg::ToggleAutoPause => {
+                self.game.state.auto_pause = !self.game.state.auto_pause;
+            }
+            Msg::ToggleGamepad => {
+                self.game.state.gamepad_enabled = !self.game.state.gamepad_enabled;
+                self.gamepad_buttons.clear();
+                self.jobs.gamepad = if self.game.state.gamepad_enabled {
+                    Some(self.poll_gamepad())
+                } else {
+                    None
+                };
+            }
+            Msg::Rebind(control) => {
+                self.rebinding = Some(control);
+                self.binding_error = None;
+            }
+            Msg::CycleHud => {
+                self.game.state.hud = self.game.state.hud.next();
+                self.storage.store(HUD_KEY, Json(&self.game.state.hud));
+            }
+            Msg::HideTrail => {
+                self.jobs.trail = None;
+                self.game.state.trail = None;
+            }
+            Msg::HideLastClear => {
+                self.jobs.clear = None;
+                self.game.state.last_clear = None;
+                self.game.state.perfect_clear = false;
+            }
+            Msg::ToggleBlindMode => {
+                self.game.state.blind_mode = !self.game.state.blind_mode;
+                self.game.state.announce("Blind mode");
+            }
+        }
+        // a game being watched again is never the one to resume
+        if self.playback.is_none() {
+            self.storage.store(KEY, Json(&self.game.state));
+        }
+        self.request_paint();
+        true
+    }
+
+    // an embedding app may unmount the game, its listeners would otherwise
+    // keep sending input to a model that is gone
+    fn destroy(&mut self) {
+        for listener in self.listeners.drain(..) {
+            listener.remove();
+        }
+        js! { @(no_return)
+            var remove = @{&self.remove_visibility_listener};
+            remove();
+        }
+        self.jobs.stop_all();
+        self.countdown = None;
+        self.frame_job = None;
+        self.paint_job = None;
+    }
+
+    fn view(&self) -> Html {
+        if let Some(versus) = &self.versus {
+            return self.view_versus(versus);
+        }
+        let ghost_y = if self.game.state.ghost_enabled {
+            Some(self.game.ghost_y())
+        } else {
+            None
+        };
+        html! {
+            <div style=self.game.state.appearance.style()>
+                { if self.game.state.hud.shows(HudSection::Stats) { self.view_stats() } else { html! {} } }
+                { if self.game.state.hud.shows(HudSection::Score) { self.view_score() } else { html! {} } }
+                { if self.game.state.hud.shows(HudSection::Settings) { self.view_settings() } else { html! {} } }
+                { if self.game.state.blind_mode {
+                    html! {
+                        <div class="sr-only" aria-live="assertive">{ &self.game.state.announcement }</div>
+                    }
+                } else {
+                    html! {}
+                }
+                }
+                { if self.game.state.hud.shows(HudSection::Hold) {
+                    html! {
+                        <div class="hold">
+                            {"Hold"}
+                            { match &self.game.state.held_piece {
+                                Some(piece_type) => self.view_piece(piece_type),
+                                None => html! {},
+                            }
+                            }
+                        </div>
+                    }
+                } else {
+                    html! {}
+                } }
+                { if self.game.state.renderer == Renderer::Canvas {
+                    self.view_canvas()
+                } else {
+                    self.view_table(&self.game, ghost_y)
+                } }
+                { if self.game.state.hud.shows(HudSection::Preview) {
+                    html! {
+                        <div class="next">
+                            {"Next"}
+                            { for self.game.state.next_queue.iter()
+                                .map(|piece_type| self.view_piece(piece_type)) }
+                        </div>
+                    }
+                } else {
+                    html! {}
+                } }
+                <div class="touch-controls">
+                    { self.view_touch_button("Rotate", Controls::Rotate) }
+                    { self.view_touch_button("Hold", Controls::Hold) }
+                    { self.view_touch_button("Drop", Controls::Bottom) }
+                </div>
+            { if let Some(left) = self.countdown {
+                html! { <div class="countdown">{ left }</div> }
+            } else if self.game.state.paused {
+                html! {
+                    <div class="paused">
+                        { format!(
+                            "Paused, press {} to resume",
+                            key_name(self.game.state.key_bindings.key(&Controls::Pause))
+                        ) }
+                    </div>
+                }
+            } else {
+                html! {}
+            }
+            }
+            { if self.playback.is_some() {
+                html! { <div class="playback">{"Replay, press any key to skip to the end"}</div> }
+            } else {
+                html! {}
+            }
+            }
+            { if self.game.state.perfect_clear {
+                html! {
+                    <div class="perfect-clear">{"Perfect Clear!"}</div>
+                }
+            } else {
+                html! {}
+            }
+            }
+            { if self.game.state.game_status.game_over {
+                html! {
+                    <>
+                    { if self.game.state.game_status.game_won {
+                        html! { <div class="game-won">{"You win!"}</div> }
+                    } else {
+                        html! { <div class="game-over">{"Game Over"}</div> }
+                    } }
+                        <p>{ if self.game.state.auto_restart { "Restarting..." } else { "Press Enter to start over" } }</p>
+                        <button onclick=self.link.callback(|_| Msg::WatchReplay)>
+                            {"Watch replay"}
+                        </button>
+                        </>
+                }
+            } else {
+                html! {}
+            }
+            }
+                <button class="restart" onclick=self.link.callback(|_| Msg::Restart)>
+                    { if self.jobs.confirm_restart.is_some() { "Really restart?" } else { "Restart" } }
+                </button>
+                <button onclick=self.link.callback(|_| Msg::StartVersus)>{"Local versus"}</button>
+                { if self.game.state.hud.shows(HudSection::Leaderboard) {
+                    self.view_leaderboard()
+                } else {
+                    html! {}
+                } }
+            </div>
+        }
+    }
+}
+
+impl Model {
+    // both versus boards side by side, each with the keys that play it
+    fn view_versus(&self, versus: &Versus) -> Html {
+        html! {
+            <div class="versus" style=self.game.state.appearance.style()>
+                { for versus.games.iter().enumerate().map(|(player, game)| {
+                    let ghost_y = if self.game.state.ghost_enabled {
+                        Some(game.ghost_y())
+                    } else {
+                        None
+                    };
+                    let keys: Vec<String> = VERSUS_KEYS[player]
+                        .iter()
+                        .map(|(key, control)| format!("{}: {}", control.as_ref(), key_name(key)))
+                        .collect();
+                    html! {
+                        <div class="versus-player">
+                            <h2>{ format!("Player {}", player + 1) }</h2>
+                            { self.view_table(game, ghost_y) }
+                            <p>{ format!("Lines: {}", game.state.game_status.rows_cleared) }</p>
+                            <p>{ format!("Garbage coming: {}", versus.pending_garbage[player]) }</p>
+                            <p class="versus-keys">{ keys.join(", ") }</p>
+                        </div>
+                    }
+                }) }
+                { if let Some(winner) = versus.winner() {
+                    html! { <div class="game-won">{ format!("Player {} wins!", winner + 1) }</div> }
+                } else {
+                    html! {}
+                } }
+                <button onclick=self.link.callback(|_| Msg::StartVersus)>{"Rematch"}</button>
+                <button onclick=self.link.callback(|_| Msg::LeaveVersus)>{"Leave versus"}</button>
+            </div>
+        }
+    }
+
+    fn view_leaderboard(&self) -> Html {
+        html! {
+            <div class="leaderboard">
+                { if let Some(score) = &self.new_score {
+                    html! {
+                        <p>
+                            { format!("New high score {}! Name ", score.score) }
+                            <input type="text"
+                                value=score.name.clone().unwrap_or_default()
+                                oninput=self.link.callback(|e: InputData| Msg::SetScoreName(e.value)) />
+                            <button onclick=self.link.callback(|_| Msg::SaveScore)>{"Save"}</button>
+                        </p>
+                    }
+                } else {
+                    html! {}
+                } }
+                { if let Some(error) = &self.score_error {
+                    html! { <p class="score-error">{ error }</p> }
+                } else {
+                    html! {}
+                } }
+                <table>
+                    <tr><th>{"#"}</th><th>{"Name"}</th><th>{"Score"}</th><th>{"Level"}</th><th>{"Lines"}</th></tr>
+                    { for self.leaderboard.entries.iter().enumerate().map(|(rank, entry)| html! {
+                        <tr>
+                            <td>{ rank + 1 }</td>
+                            <td>{ entry.name.as_ref().map_or("-", String::as_str) }</td>
+                            <td>{ entry.score }</td>
+                            <td>{ entry.level }</td>
+                            <td>{ entry.lines }</td>
+                        </tr>
+                    }) }
+                </table>
+                { if self.sprint_times.times.is_empty() {
+                    html! {}
+                } else {
+                    html! {
+                        <table class="sprint-times">
+                            <tr><th>{"#"}</th><th>{"Sprint 40L"}</th></tr>
+                            { for self.sprint_times.times.iter().enumerate()
+                                .map(|(rank, time)| html! {
+                                    <tr><td>{ rank + 1 }</td><td>{ format_time(*time) }</td></tr>
+                                }) }
+                        </table>
+                    }
+                } }
+                <button onclick=self.link.callback(|_| Msg::ClearScores)>{"Clear scores"}</button>
+            </div>
+        }
+    }
+
+    fn view_stats(&self) -> Html {
+        html! {
+            <>
+                <p>{ format!("Level: {}", self.game.state.game_status.level) }</p>
+                <p>{ format!("Rows cleared: {}", self.game.state.game_status.rows_cleared) }</p>
+                <p>{ format!(
+                    "{} lines to level {}",
+                    self.game.state.game_status.lines_to_next_level(),
+                    self.game.state.game_status.level + 1
+                ) }</p>
+                { if self.game.state.game_status.combo > 0 {
+                    html! {
+                        <p class="combo">{ format!("Combo: {}", self.game.state.game_status.combo) }</p>
+                    }
+                } else {
+                    html! {}
+                }
+                }
+                { if self.game.state.game_status.back_to_back {
+                    html! { <p class="back-to-back">{"B2B"}</p> }
+                } else {
+                    html! {}
+                }
+                }
+                <p>{ format!("Finesse faults: {}", self.game.state.game_status.finesse_faults) }</p>
+                { if let Some((branch, count)) = self.game.state.history.branches() {
+                    html! { <p class="branch">{ format!("Redo branch {}/{}", branch, count) }</p> }
+                } else {
+                    html! {}
+                } }
+                <p>{ format!(
+                    "Gravity: {:.2} cells/s",
+                    cells_per_second(self.game.state.gravity())
+                ) }</p>
+                { if let Some(last_clear) = &self.game.state.last_clear {
+                    html! {
+                        <p class="last-clear">{ last_clear.as_ref() }</p>
+                    }
+                } else {
+                    html! {}
+                }
+                }
+                { match self.game.state.deepest_well() {
+                    Some((col, depth)) if depth >= WELL_WARNING_DEPTH => html! {
+                        <p class="well-warning">
+                            { format!("Needs an I: {}-deep well in column {}", depth, col + 1) }
+                        </p>
+                    },
+                    _ => html! {},
+                }
+                }
+                <table class="droughts">
+                    <tr>{ for tetrominoes().map(|piece| html! { <th>{ piece.as_ref() }</th> }) }</tr>
+                    <tr>
+                    { for tetrominoes().map(|piece| html! {
+                        <td>{ self.game.state.droughts.get(&piece).cloned().unwrap_or(0) }</td>
+                    }) }
+                    </tr>
+                </table>
+                <table class="piece-counts">
+                    <caption>{"Pieces dealt"}</caption>
+                    <tr>{ for tetrominoes().map(|piece| html! { <th>{ piece.as_ref() }</th> }) }</tr>
+                    <tr>
+                    { for tetrominoes().map(|piece| html! {
+                        <td>{ self.game.state.piece_counts.get(&piece).cloned().unwrap_or(0) }</td>
+                    }) }
+                    </tr>
+                </table>
+            </>
+        }
+    }
+
+    // small standalone table of a piece in its spawn orientation
+    fn view_piece(&self, piece_type: &PieceType) -> Html {
+        let (_, shape) = self.game.state.pieces.spawn(piece_type.clone());
+        html! {
+            <table class="piece">
+            { for (0..shape.n_rows).map(|row| html! {
+                <tr>
+                { for (0..shape.n_cols).map(|col| self.view_cell(shape.get(row, col))) }
+                </tr>
+            }) }
+            </table>
+        }
+    }
+
+    // a cell of the board or a preview, marked with its piece's letter in
+    // glyph mode so pieces can be told apart without color
+    fn view_cell(&self, cell: &str) -> Html {
+        self.view_cell_with_class(cell, self.game.state.theme.class(cell))
+    }
+
+    fn view_cell_with_class(&self, cell: &str, class: String) -> Html {
+        let glyph = if self.game.state.glyphs && is_block(cell) {
+            cell
+        } else {
+            ""
+        };
+        html! {
+            <td class=class>{ glyph }</td>
+        }
+    }
+
+    // the board of `game`, drawn in the player's own theme
+    fn view_table(&self, game: &Game, ghost_y: Option<isize>) -> Html {
+        let state = &game.state;
+        // the piece flashes once nothing but locking is left for it
+        let force_locking = game.is_force_locking();
+        html! {
+            <table class=state.board_class()>
+            <>
+        { for (state.stage.n_rows - state.visible_rows()..state.stage.n_rows).map(|row| {
+            html! {
+              <>
+                <tr>
+                {
+                    for (0..state.stage.n_cols).map(|col| {
+                        let cell = state.cell(row, col);
+                        let Position { x, y } = state.player.position;
+                        let rowi = row as isize;
+                        let coli = col as isize;
+                        let trail = state.trail.as_ref().and_then(|trail| trail.opacity(rowi, coli));
+                        let ghost = ghost_y.map_or(false, |ghost_y| state.player.fills(x, ghost_y, rowi, coli));
+                        match trail {
+                            _ if ghost && cell == PieceType::E.as_ref() => html! {
+                                <td class=self.game.state.theme.class("ghost")/>
+                            },
+                            Some(opacity) if cell == PieceType::E.as_ref() => html! {
+                                <td class="cell-trail" style=format!("opacity: {:.2}", opacity)/>
+                            },
+                            _ if force_locking && state.player.fills(x, y, rowi, coli) => {
+                                let class = format!("{} locking", self.game.state.theme.class(cell));
+                                self.view_cell_with_class(cell, class)
+                            }
+                            _ => self.view_cell(cell),
+                        }
+                    })
+                }
+                </tr>
+              </>
+            }
+        })}
+          </>
+          </table>
+        }
+    }
+
+    // px of a stage cell, a big mode cell covers the gap between the four
+    // board cells it stands for
+    fn cell_size(&self) -> usize {
+        let scale = self.game.state.board_scale();
+        CELL_SIZE * scale + self.game.state.appearance.cell_gap * (scale - 1)
+    }
+
+    // painted after rendering, see `paint`
+    fn view_canvas(&self) -> Html {
+        let Vec2D { n_cols, .. } = self.game.state.stage;
+        let n_rows = self.game.state.visible_rows();
+        let pitch = (self.cell_size() + self.game.state.appearance.cell_gap) as f64;
+        html! {
+            <canvas class=self.game.state.board_class() ref=self.canvas.clone()
+                width=(pitch * n_cols as f64) as usize
+                height=(pitch * n_rows as f64) as usize />
+        }
+    }
+
+    // the canvas only exists once the view has rendered, so it is painted on
+    // the next animation frame
+    fn request_paint(&mut self) {
+        if self.game.state.renderer == Renderer::Canvas && self.paint_job.is_none() {
+            let handle = self
+                .render
+                .request_animation_frame(self.link.callback(|_| Msg::Paint));
+            self.paint_job = Some(handle);
+        }
+    }
+
+    fn paint(&self) {
+        let canvas = match NodeRef::try_into::<CanvasElement>(&self.canvas) {
+            Some(canvas) => canvas,
+            None => return,
+        };
+        let context: CanvasRenderingContext2d = match canvas.get_context() {
+            Ok(context) => context,
+            Err(_) => return,
+        };
+        let state = &self.game.state;
+        let gap = state.appearance.cell_gap as f64;
+        let cell_size = self.cell_size() as f64;
+        let pitch = cell_size + gap;
+        context.set_fill_style_color(&state.appearance.background);
+        context.fill_rect(0.0, 0.0, canvas.width() as f64, canvas.height() as f64);
+
+        let ghost_y = if state.ghost_enabled {
+            Some(self.game.ghost_y())
+        } else {
+            None
+        };
+        let x = state.player.position.x;
+        let first_row = state.stage.n_rows - state.visible_rows();
+        for row in first_row..state.stage.n_rows {
+            for col in 0..state.stage.n_cols {
+                let ghost = matches!(ghost_y, Some(ghost_y) if state.player.fills(x, ghost_y, row as isize, col as isize));
+                let cell = state.cell(row, col);
+                let trail = state.trail.as_ref().and_then(|trail| trail.opacity(row as isize, col as isize));
+                let trail_color;
+                let color = match state.theme.color(cell) {
+                    Some(color) => color,
+                    None if ghost => state.theme.color("ghost").unwrap_or_default(),
+                    None => match trail {
+                        Some(opacity) => {
+                            trail_color = format!("rgba(255, 255, 255, {:.2})", opacity);
+                            &trail_color
+                        }
+                        None => continue,
+                    },
+                };
+                let left = col as f64 * pitch + gap / 2.0;
+                let top = (row - first_row) as f64 * pitch + gap / 2.0;
+                context.set_fill_style_color(color);
+                context.fill_rect(left, top, cell_size, cell_size);
+                if state.glyphs && is_block(cell) {
+                    context.set_fill_style_color("black");
+                    context.fill_text(cell, left + 3.0, top + cell_size - 3.0, None);
+                }
+            }
+        }
+    }
+
+    // played on mousedown, which taps also fire, so the button never takes the
+    // keyboard focus and Space or Enter can't press it
+    fn view_touch_button(&self, label: &str, control: Controls) -> Html {
+        html! {
+            <button onmousedown=self.link.callback(move |e: MouseDownEvent| {
+                e.prevent_default();
+                Msg::Input(control.clone())
+            })>{ label }</button>
+        }
+    }
+
+    fn view_score(&self) -> Html {
+        html! {
+            <>
+                <p>{ format!("Score: {}", self.game.state.game_status.score) }</p>
+                <p>{ format!("Elapsed: {}:{:02}", self.game.state.elapsed / 60, self.game.state.elapsed % 60) }</p>
+                { if self.game.state.mode == GameMode::Sprint {
+                    let running = self.game.state.played.at(Date::now());
+                    let time = self.game.state.sprint_time().unwrap_or(running);
+                    html! { <p class="sprint-timer">{ format_time(time) }</p> }
+                } else {
+                    html! {}
+                } }
+                { if let Some(lines_goal) = self.game.state.lines_goal {
+                    html! {
+                        <p>{ format!(
+                            "Lines left: {}",
+                            lines_goal.saturating_sub(self.game.state.game_status.rows_cleared)
+                        ) }</p>
+                    }
+                } else {
+                    html! {}
+                }
+                }
+                { if let Some(pieces_left) = self.game.state.pieces_left {
+                    html! { <p>{ format!("Pieces left: {}", pieces_left) }</p> }
+                } else {
+                    html! {}
+                }
+                }
+                { if let Some(time_left) = self.game.state.time_left {
+                    html! {
+                        <>
+                            <p>{ format!("Time: {}:{:02}", time_left / 60, time_left % 60) }</p>
+                            <p>{ format!("Best Ultra score: {}", self.game.state.ultra_best) }</p>
+                        </>
+                    }
+                } else {
+                    html! {}
+                }
+                }
+            </>
+        }
+    }
+
+    fn view_settings(&self) -> Html {
+        html! {
+            <div class="settings">
+                <label>
+                    <input type="checkbox"
+                        checked=self.game.state.blind_mode
+                        onclick=self.link.callback(|_| Msg::ToggleBlindMode) />
+                    {"Blind mode"}
+                </label>
+                <label>
+                    <input type="checkbox"
+                        checked=self.game.state.auto_restart
+                        onclick=self.link.callback(|_| Msg::ToggleAutoRestart) />
+                    {"Auto restart"}
+                </label>
+                <label>
+                    <input type="checkbox"
+                        checked=(self.game.state.safety_rows > 0)
+                        onclick=self.link.callback(|_| Msg::ToggleSafetyRows) />
+                    {"Beginner safety rows (next game)"}
+                </label>
+                <label>
+                    <input type="checkbox"
+                        checked=self.game.state.safe_first_piece
+                        onclick=self.link.callback(|_| Msg::ToggleSafeFirstPiece) />
+                    {"No S, Z or O as first piece"}
+                </label>
+                <label>
+                    <input type="checkbox"
+                        checked=self.game.state.scaled_clear_delay
+                        onclick=self.link.callback(|_| Msg::ToggleScaledClearDelay) />
+                    {"Longer pause for bigger clears"}
+                </label>
+                <label>
+                    <input type="checkbox"
+                        checked=(self.game.state.randomizer == Randomizer::Bag)
+                        onclick=self.link.callback(|_| Msg::ToggleRandomizer) />
+                    {"7-bag randomizer"}
+                </label>
+                <label>
+                    <input type="checkbox"
+                        checked=self.game.state.ghost_enabled
+                        onclick=self.link.callback(|_| Msg::ToggleGhost) />
+                    {"Ghost piece"}
+                </label>
+                <label>
+                    <input type="checkbox"
+                        checked=self.game.state.trail_enabled
+                        onclick=self.link.callback(|_| Msg::ToggleTrail) />
+                    {"Hard drop trail"}
+                </label>
+                <label>
+                    <input type="checkbox"
+                        checked=self.game.state.gamepad_enabled
+                        onclick=self.link.callback(|_| Msg::ToggleGamepad) />
+                    {"Gamepad"}
+                </label>
+                <label>
+                    <input type="checkbox"
+                        checked=self.game.state.auto_pause
+                        onclick=self.link.callback(|_| Msg::ToggleAutoPause) />
+                    {"Pause when the tab is hidden"}
+                </label>
+                <label>
+                    <input type="checkbox"
+                        checked=(self.game.state.renderer == Renderer::Canvas)
+                        onclick=self.link.callback(|_| Msg::ToggleCanvas) />
+                    {"Draw the board on a canvas"}
+                </label>
+                <label>
+                    <input type="checkbox"
+                        checked=self.game.state.glyphs
+                        onclick=self.link.callback(|_| Msg::ToggleGlyphs) />
+                    {"Mark pieces with letters"}
+                </label>
+                <label>
+                    <input type="checkbox"
+                        checked=self.game.state.muted
+                        onclick=self.link.callback(|_| Msg::ToggleMute) />
+                    {"Mute"}
+                </label>
+                <label>
+                    {"Preview "}
+                    <input type="number" min="1" max=MAX_PREVIEW_COUNT
+                        value=self.game.state.preview_count
+                        oninput=self.link.callback(|e: InputData| Msg::SetPreviewCount(e.value)) />
+                </label>
+                <label>
+                    {"Marathon goal (lines, 0 for endless, next game) "}
+                    <input type="number" min="0"
+                        value=self.game.state.marathon_goal
+                        oninput=self.link.callback(|e: InputData| Msg::SetMarathonGoal(e.value)) />
+                </label>
+                <label>
+                    {"Lock resets "}
+                    <input type="number" min="0" max=MAX_LOCK_RESET_CAP
+                        value=self.game.state.lock_reset_cap
+                        oninput=self.link.callback(|e: InputData| Msg::SetLockResetCap(e.value)) />
+                </label>
+                <label>
+                    {"Countdown (s) "}
+                    <input type="number" min="0" max=MAX_COUNTDOWN
+                        value=self.game.state.countdown_secs
+                        oninput=self.link.callback(|e: InputData| Msg::SetCountdown(e.value)) />
+                </label>
+                <label>
+                    {"Start level "}
+                    <input type="number" min="1" max=MAX_START_LEVEL
+                        value=self.game.state.start_level
+                        oninput=self.link.callback(|e: InputData| Msg::SetStartLevel(e.value)) />
+                </label>
+                <label>
+                    {"Fastest gravity (ms per row) "}
+                    <input type="number" min=MIN_DURATION as u64
+                        value=self.game.state.gravity_floor_ms
+                        oninput=self.link.callback(|e: InputData| Msg::SetGravityFloor(e.value)) />
+                </label>
+                <label>
+                    {"DAS (ms) "}
+                    <input type="number" min="0"
+                        value=self.game.state.das_ms
+                        oninput=self.link.callback(|e: InputData| Msg::SetDas(e.value)) />
+                </label>
+                <label>
+                    {"DAS cut (ms, 0 is off) "}
+                    <input type="number" min="0"
+                        value=self.game.state.das_cut_ms
+                        oninput=self.link.callback(|e: InputData| Msg::SetDasCut(e.value)) />
+                </label>
+                <label>
+                    {"ARR (ms) "}
+                    <input type="number" min="0"
+                        value=self.game.state.arr_ms
+                        oninput=self.link.callback(|e: InputData| Msg::SetArr(e.value)) />
+                </label>
+                <label>
+                    {"Soft drop (ms, 0 follows gravity) "}
+                    <input type="number" min="0"
+                        value=self.game.state.soft_drop_ms
+                        oninput=self.link.callback(|e: InputData| Msg::SetSoftDropRate(e.value)) />
+                </label>
+                <label>
+                    {"Cell gap "}
+                    <input type="number" min="0"
+                        value=self.game.state.appearance.cell_gap
+                        oninput=self.link.callback(|e: InputData| Msg::SetCellGap(e.value)) />
+                </label>
+                <label>
+                    {"Cell radius "}
+                    <input type="number" min="0"
+                        value=self.game.state.appearance.cell_radius
+                        oninput=self.link.callback(|e: InputData| Msg::SetCellRadius(e.value)) />
+                </label>
+                <label>
+                    {"Background "}
+                    <input type="text"
+                        value=&self.game.state.appearance.background
+                        oninput=self.link.callback(|e: InputData| Msg::SetBackground(e.value)) />
+                </label>
+                <button onclick=self.link.callback(|_| Msg::CycleHud)>
+                    { format!("HUD: {}", self.game.state.hud.as_ref()) }
+                </button>
+                <button onclick=self.link.callback(|_| Msg::CycleTheme)>
+                    { format!("Theme: {}", self.game.state.theme.as_ref()) }
+                </button>
+                <button onclick=self.link.callback(|_| Msg::CycleGravity)>
+                    { format!("Gravity: {}", self.game.state.gravity_profile.as_ref()) }
+                </button>
+                <button onclick=self.link.callback(|_| Msg::CycleRotationSystem)>
+                    { format!("Rotation: {}", self.game.state.rotation_system.as_ref()) }
+                </button>
+                <label>
+                    <input type="checkbox"
+                        checked=self.game.state.hold_keeps_rotation
+                        onclick=self.link.callback(|_| Msg::ToggleHoldKeepsRotation) />
+                    {"Held pieces keep their rotation"}
+                </label>
+                <button onclick=self.link.callback(|_| Msg::CycleMode)>
+                    { format!("Mode: {} (next game)", self.game.state.next_mode.as_ref()) }
+                </button>
+                <table class="key-bindings">
+                { for self.game.state.key_bindings.keys.iter().map(|(control, key)| {
+                    let rebind = control.clone();
+                    html! {
+                        <tr>
+                            <td>{ control.as_ref() }</td>
+                            <td>
+                                <button onclick=self.link.callback(move |_| Msg::Rebind(rebind.clone()))>
+                                    { if self.rebinding.as_ref() == Some(control) { "Press a key" } else { key_name(key) } }
+                                </button>
+                            </td>
+                        </tr>
+                    }
+                }) }
+                </table>
+                { if let Some(error) = &self.binding_error {
+                    html! { <p class="binding-error">{ error }</p> }
+                } else {
+                    html! {}
+                } }
+                <label>
+                    {"Piece sequence (next game) "}
+                    <input type="text" placeholder="e.g. TSZ IO"
+                        value=&self.script_text
+                        oninput=self.link.callback(|e: InputData| Msg::SetScript(e.value)) />
+                </label>
+                <label>
+                    <input type="checkbox"
+                        checked=self.game.state.loop_script
+                        onclick=self.link.callback(|_| Msg::ToggleLoopScript) />
+                    {"Loop the sequence (next game)"}
+                </label>
+                { if let Some(error) = &self.script_error {
+                    html! { <p class="script-error">{ error }</p> }
+                } else {
+                    html! {}
+                } }
+                <div class="puzzle">
+                    <textarea placeholder="Board rows, then the pieces, e.g.\nXXXX..XXXX\nO"
+                        value=&self.puzzle_text
+                        oninput=self.link.callback(|e: InputData| Msg::SetPuzzleText(e.value)) />
+                    <button onclick=self.link.callback(|_| Msg::StartPuzzle)>{"Start puzzle"}</button>
+                    { if self.game.state.puzzle.is_some() {
+                        html! {
+                            <button onclick=self.link.callback(|_| Msg::LeavePuzzle)>
+                                {"Leave puzzle (next game)"}
+                            </button>
+                        }
+                    } else {
+                        html! {}
+                    } }
+                    { if let Some(error) = &self.puzzle_error {
+                        html! { <p class="puzzle-error">{ format!("Invalid puzzle: {}", error) }</p> }
+                    } else {
+                        html! {}
+                    } }
+                </div>
+                <div class="replay">
+                    <textarea
+                        value=&self.replay_text
+                        oninput=self.link.callback(|e: InputData| Msg::SetReplayText(e.value)) />
+                    <button onclick=self.link.callback(|_| Msg::ExportReplay)>{"Export replay"}</button>
+                    <button onclick=self.link.callback(|_| Msg::LoadReplay)>{"Load replay"}</button>
+                    <a class="share" href=share_fragment(self.game.state.replay.settings())>
+                        {"Link to this game"}
+                    </a>
+                    { if let Some(error) = &self.replay_error {
+                        html! { <p class="replay-error">{ format!("Invalid replay: {}", error) }</p> }
+                    } else {
+                        html! {}
+                    } }
+                </div>
+            </div>
+        }
+    }
+
+    // a key or button going down
+    fn press(&mut self, control: Controls) {
+        // the paused game waits out a versus match, touch and gamepad included
+        if self.versus.is_some() {
+            return;
+        }
+        // a held key is one press, however far DAS carries the piece
+        if let Controls::Left
+        | Controls::Right
+        | Controls::Rotate
+        | Controls::RotateCCW
+        | Controls::Rotate180 = control
+        {
+            if self.countdown.is_none() && !self.game.state.paused {
+                self.game.state.player.inputs += 1;
+            }
+        }
+        match control {
+            Controls::Left | Controls::Right => self.link.send_message(Msg::ShiftStart(control)),
+            Controls::SoftDrop => self.link.send_message(Msg::SoftDropStart),
+            // a hard drop ends a held soft drop, the next piece waits for
+            // the key to be pressed again
+            Controls::Bottom => {
+                self.link.send_message(Msg::SoftDropStop);
+                self.link.send_message(Msg::Input(control));
+            }
+            Controls::Pause => self.toggle_pause(),
+            control => self.link.send_message(Msg::Input(control)),
+        }
+    }
+
+    fn start_game(&mut self) {
+        info!("Starting game!");
+        self.game.state.played.start(Date::now());
+        self.link.send_message(Msg::StartInterval);
+    }
+
+    // counts down countdown_secs, then starts or resumes the game
+    fn start_countdown(&mut self) {
+        self.countdown = Some(self.game.state.countdown_secs);
+        let handle = self.interval.spawn(
+            Duration::from_secs(1),
+            self.link.callback(|_| Msg::CountdownTick),
+        );
+        self.jobs.countdown = Some(Box::new(handle));
+    }
+
+    // pausing during a countdown calls it off, resuming counts down first
+    fn toggle_pause(&mut self) {
+        if self.countdown.is_some() {
+            self.countdown = None;
+            self.jobs.countdown = None;
+        } else if self.game.state.paused && self.game.state.countdown_secs > 0 {
+            self.start_countdown();
+        } else {
+            self.link.send_message(Msg::Move(Controls::Pause));
+        }
+    }
+
+    // a fresh local versus match, the game being played waits paused
+    fn start_versus(&mut self) {
+        if self.jobs.gravity.is_some() && !self.game.state.paused {
+            let outcome = self.play(ReplayEvent::Step(Controls::Pause));
+            self.apply(outcome);
+        }
+        let settings = Settings {
+            seed: None,
+            ..self.game.state.settings(0)
+        };
+        self.versus = Some(Versus::new(settings));
+        self.last_tick_at = Date::now();
+        let handle = self.interval.spawn(
+            Duration::from_millis(GRAVITY_STEP),
+            self.link.callback(|_| Msg::VersusTick),
+        );
+        self.jobs.versus = Some(Box::new(handle));
+    }
+
+    // local versus has no DAS, held keys repeat at the keyboard's own rate
+    fn press_versus(&mut self, key: &str) -> bool {
+        match (self.versus.as_mut(), versus_control(key)) {
+            (Some(versus), Some((player, control))) => {
+                versus.play(player, ReplayEvent::Step(control));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn release(&mut self, control: Controls) {
+        match control {
+            Controls::Left | Controls::Right => self.link.send_message(Msg::ShiftStop(control)),
+            Controls::SoftDrop => self.link.send_message(Msg::SoftDropStop),
+            _ => {}
+        }
+    }
+
+    fn poll_gamepad(&mut self) -> Box<dyn Task> {
+        let handle = self.interval.spawn(
+            Duration::from_millis(GAMEPAD_POLL),
+            self.link.callback(|_| Msg::PollGamepad),
+        );
+        Box::new(handle)
+    }
+
+    // runs an input against the engine and records it for the replay
+    fn play(&mut self, event: ReplayEvent) -> StepOutcome {
+        let at = self.game.state.played.at(Date::now());
+        self.game.state.replay.record(at, event.clone());
+        let rotated = matches!(
+            event,
+            ReplayEvent::Step(Controls::Rotate)
+                | ReplayEvent::Step(Controls::RotateCCW)
+                | ReplayEvent::Step(Controls::Rotate180)
+        );
+        let hard_dropped = matches!(event, ReplayEvent::Step(Controls::Bottom));
+        let level = self.game.state.game_status.level;
+        let outcome = self.game.play(event);
+        let level_up = self.game.state.game_status.level > level;
+        if !self.game.state.muted {
+            if let Some(sound) = Sound::after(rotated, hard_dropped, level_up, &outcome) {
+                sound.play();
+            }
+        }
+        outcome
+    }
+
+    fn load_replay(&mut self, json: &str) -> Result<(), String> {
+        let replay: Replay = serde_json::from_str(json).map_err(|error| error.to_string())?;
+        if !replay.can_replay() {
+            return Err("A game dealt by custom pieces can't be played again".into());
+        }
+        let mut game = Game::from_replay(replay);
+        game.state.copy_preferences(&self.game.state);
+        self.stop_jobs();
+        self.game = game;
+        Ok(())
+    }
+
+    // a new game on the puzzle in `text`, played with the current settings
+    fn start_puzzle(&mut self, text: &str) -> Result<(), String> {
+        let puzzle = Puzzle::parse(text)?;
+        let state = &self.game.state;
+        let settings = Settings {
+            seed: state.seed,
+            ..state.settings(0)
+        };
+        let mut game = Game {
+            state: State::with_puzzle(settings, puzzle)?,
+        };
+        game.state.copy_preferences(&self.game.state);
+        self.stop_jobs();
+        self.game = game;
+        Ok(())
+    }
+
+    // plays the rest of a watched game in one go, it ends over like it did
+    fn finish_playback(&mut self) {
+        if let Some(mut playback) = self.playback.take() {
+            playback.advance(&mut self.game, f64::INFINITY);
+        }
+        self.game.state.played.stop(Date::now());
+        self.jobs.playback = None;
+    }
+
+    fn stop_jobs(&mut self) {
+        self.jobs.stop_game();
+        self.countdown = None;
+    }
+
+    // starts the timers that follow what the engine did
+    fn apply(&mut self, outcome: StepOutcome) {
+        match outcome {
+            StepOutcome::GameOver => self.end_game(),
+            StepOutcome::Grounded => {
+                if self.jobs.lock.is_none() {
+                    self.start_lock_delay();
+                }
+            }
+            StepOutcome::LockReset => self.start_lock_delay(),
+            StepOutcome::Locked { rows_cleared } => {
+                self.jobs.lock = None;
+                self.gravity_acc = 0.0;
+                if self.game.state.trail.is_some() {
+                    self.show_trail();
+                }
+                if rows_cleared > 0 {
+                    self.show_last_clear(rows_cleared);
+                }
+            }
+            StepOutcome::Paused => {
+                info!("Pausing game");
+                let now = Date::now();
+                self.gravity_acc += now - self.last_tick_at;
+                self.game.state.played.stop(now);
+                self.link.send_message(Msg::Cancel);
+            }
+            StepOutcome::Resumed => {
+                info!("Resuming game");
+                // the piece falls when it would have, had the game not paused
+                self.game.state.played.start(Date::now());
+                self.start_gravity();
+            }
+            StepOutcome::Moved | StepOutcome::Blocked => {}
+        }
+    }
+
+    fn start_das(&mut self) {
+        if self.jobs.gravity.is_some() {
+            self.game.state.cut_gravity(&mut self.gravity_acc);
+        }
+        let handle = self.timeout.spawn(
+            Duration::from_millis(self.game.state.das_ms),
+            self.link.callback(|_| Msg::Das),
+        );
+        self.jobs.shift = Some(Box::new(handle));
+    }
+
+    // an ARR of 0 moves the piece as far as it goes in one go
+    fn shift_to_wall(&mut self) {
+        if let Some(direction) = self.game.state.held_directions.last().cloned() {
+            shift_to_wall(direction, |event| {
+                let outcome = self.play(event);
+                self.apply(outcome.clone());
+                outcome
+            });
+        }
+    }
+
+    fn start_lock_delay(&mut self) {
+        let handle = self.timeout.spawn(
+            Duration::from_millis(LOCK_DELAY),
+            self.link.callback(|_| Msg::LockTick),
+        );
+        self.jobs.lock = Some(Box::new(handle));
+    }
+
+    fn start_gravity(&mut self) {
+        self.last_tick_at = Date::now();
+        let handle = self
+            .interval
+            .spawn(Duration::from_millis(GRAVITY_STEP), self.callback_tick.clone());
+        self.jobs.gravity = Some(Box::new(handle));
+        info!("Interval started!");
+    }
+
+    // plays a Second for each one the clock ran past since the last, a pause
+    // keeps whatever part of a second was already played
+    fn count_seconds(&mut self, now: f64) -> bool {
+        let seconds = self.game.state.played.seconds(now);
+        let mut counted = false;
+        while self.game.state.elapsed < seconds && !self.game.is_over() && !self.game.state.paused {
+            let outcome = self.play(ReplayEvent::Second);
+            self.apply(outcome);
+            counted = true;
+        }
+        counted
+    }
+
+    fn end_game(&mut self) {
+        self.jobs.lock = None;
+        self.game.state.played.stop(Date::now());
+        self.link.send_message(Msg::Cancel);
+        let state = &mut self.game.state;
+        if state.mode == GameMode::Ultra && state.game_status.score > state.ultra_best {
+            state.ultra_best = state.game_status.score;
+            self.storage.store(ULTRA_BEST_KEY, Json(&state.ultra_best));
+        }
+        if let Some(time) = state.sprint_time() {
+            let rank = self.sprint_times.insert(time);
+            self.storage.store(SPRINT_TIMES_KEY, Json(&self.sprint_times));
+            if let Some(rank) = rank {
+                state.announce(&format!("Sprint time {}, #{}", format_time(time), rank));
+            }
+        }
+        // practice games can be undone and puzzles start half built, so they
+        // stay off the leaderboard
+        let state = &self.game.state;
+        let status = &state.game_status;
+        let ranked = state.mode != GameMode::Practice && state.puzzle.is_none();
+        if ranked && self.leaderboard.qualifies(status.score) {
+            self.new_score = Some(Score {
+                score: status.score,
+                level: status.level,
+                lines: status.rows_cleared,
+                name: None,
+                replay: Some(self.game.state.replay.clone()),
+            });
+        }
+        if let Some(delay) = self.game.state.restart_delay() {
+            let handle = self.timeout.spawn(
+                Duration::from_millis(delay),
+                self.link.callback(|_| Msg::AutoRestart),
+            );
+            self.jobs.restart = Some(Box::new(handle));
+        }
+    }
+
+    fn show_trail(&mut self) {
+        let handle = self.timeout.spawn(
+            Duration::from_millis(TRAIL_DURATION),
+            self.link.callback(|_| Msg::HideTrail),
+        );
+        self.jobs.trail = Some(Box::new(handle));
+    }
+
+    fn show_last_clear(&mut self, rows_cleared: usize) {
+        let duration = last_clear_duration(rows_cleared, self.game.state.scaled_clear_delay);
+        let handle = self.timeout.spawn(
+            Duration::from_millis(duration),
+            self.link.callback(|_| Msg::HideLastClear),
+        );
+        self.jobs.clear = Some(Box::new(handle));
+    }
+}
+
+impl State {
+    pub fn new(settings: Settings) -> State {
+        State::with_pieces(settings, PieceSet::default())
+    }
+
+    /// A fresh state that deals the pieces of `pieces` instead of the seven
+    /// tetrominoes.
+    pub fn with_pieces(settings: Settings, pieces: PieceSet) -> State {
+        let scale = settings.mode.scale();
+        let seed = settings.seed.unwrap_or_else(random);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let (script, loop_script) = settings.script();
+        let mut piece_source =
+            settings.randomizer.piece_source(&mut rng, &pieces, script, loop_script);
+        let player = initialize_player(
+            piece_source.as_mut(),
+            &pieces,
+            settings.safe_first_piece && script.is_empty(),
+            settings.n_cols / scale,
+            settings.safety_rows,
+        );
+        let next_queue = initialize_queue(piece_source.as_mut(), settings.preview_count);
+        let mut state = State {
+            stage: initialize_stage(
+                settings.n_rows / scale + BUFFER_ROWS + settings.safety_rows,
+                settings.n_cols / scale,
+            ),
+            player,
+            game_status: initialize_game_status(settings.start_level),
+            blind_mode: false,
+            announcement: "".into(),
+            last_clear: None,
+            perfect_clear: false,
+            trail: None,
+            trail_enabled: true,
+            hud: HudMode::Full,
+            droughts: initialize_droughts(),
+            piece_counts: initialize_droughts(),
+            auto_restart: false,
+            appearance: Appearance::default(),
+            safety_rows: settings.safety_rows,
+            safe_first_piece: settings.safe_first_piece,
+            next_queue,
+            held_piece: None,
+            held_rotation: 0,
+            hold_used: false,
+            scaled_clear_delay: false,
+            randomizer: settings.randomizer,
+            ghost_enabled: true,
+            paused: false,
+            lock_resets: 0,
+            lock_reset_cap: settings.lock_reset_cap,
+            rotation_system: settings.rotation_system,
+            hold_keeps_rotation: settings.hold_keeps_rotation,
+            held_directions: Vec::new(),
+            soft_dropping: false,
+            das_ms: DAS_DEFAULT,
+            das_cut_ms: 0,
+            gravity_floor_ms: settings.gravity_floor_ms,
+            gravity_profile: GravityProfile::Guideline,
+            arr_ms: ARR_DEFAULT,
+            soft_drop_ms: settings.soft_drop_ms,
+            seed: settings.seed,
+            start_level: settings.start_level,
+            stage_rows: settings.n_rows,
+            stage_cols: settings.n_cols,
+            mode: settings.mode,
+            next_mode: settings.mode,
+            elapsed: 0,
+            played: PlayClock::default(),
+            time_left: settings.mode.time_limit(),
+            marathon_goal: settings.marathon_goal,
+            lines_goal: settings.mode.lines_goal(settings.marathon_goal),
+            ultra_best: 0,
+            preview_count: settings.preview_count,
+            script: settings.script.clone(),
+            loop_script: settings.loop_script,
+            puzzle: settings.puzzle.clone(),
+            pieces_left: None,
+            countdown_secs: COUNTDOWN_DEFAULT,
+            key_bindings: KeyBindings::default(),
+            gamepad_enabled: false,
+            auto_pause: true,
+            renderer: Renderer::Table,
+            glyphs: false,
+            muted: false,
+            theme: Theme::Classic,
+            replay: Replay::new(Settings {
+                seed: Some(seed),
+                ..settings
+            }),
+            rng,
+            piece_source,
+            custom_source: false,
+            pieces,
+            history: UndoTree::default(),
+        };
+        state.set_up_puzzle();
+        state.record_spawn();
+        state
+    }
+
+    /// A fresh state whose whole piece sequence is determined by `seed`.
+    pub fn with_seed(seed: u64) -> State {
+        State::new(Settings {
+            seed: Some(seed),
+            ..Settings::default()
+        })
+    }
+
+    fn settings(&self, seed: u64) -> Settings {
+        Settings {
+            n_rows: self.stage_rows,
+            n_cols: self.stage_cols,
+            start_level: self.start_level,
+            safety_rows: self.safety_rows,
+            safe_first_piece: self.safe_first_piece,
+            randomizer: self.randomizer,
+            mode: self.mode,
+            preview_count: self.preview_count,
+            lock_reset_cap: self.lock_reset_cap,
+            gravity_floor_ms: self.gravity_floor_ms,
+            soft_drop_ms: self.soft_drop_ms,
+            rotation_system: self.rotation_system,
+            hold_keeps_rotation: self.hold_keeps_rotation,
+            marathon_goal: self.marathon_goal,
+            script: self.script.clone(),
+            loop_script: self.loop_script,
+            custom_pieces: self.custom_source,
+            puzzle: self.puzzle.clone(),
+            seed: Some(seed),
+        }
+    }
+
+    pub fn export_replay(&self) -> String {
+        serde_json::to_string(&self.replay).unwrap_or_default()
+    }
+
+    /// A fresh state whose stage ends in `rows`, top to bottom, e.g.
+    /// `["..XX.", "XXXXX"]`: `.` is empty, `X` garbage and a piece letter a
+    /// block of that piece. Every row must be as wide as the stage, and the
+    /// rows above them stay empty.
+    pub fn with_board(settings: Settings, rows: &[&str]) -> Result<State, String> {
+        let mut state = State::new(settings);
+        state.fill_stage(rows)?;
+        Ok(state)
+    }
+
+    /// A fresh "clear in N pieces" game on the board of `puzzle`, refused if
+    /// the board doesn't fit the stage or has no garbage to clear.
+    pub fn with_puzzle(settings: Settings, puzzle: Puzzle) -> Result<State, String> {
+        if !puzzle.board.iter().any(|row| row.contains('X')) {
+            return Err("The puzzle board has no garbage to clear".into());
+        }
+        if puzzle.pieces.is_empty() {
+            return Err("A puzzle needs at least one piece".into());
+        }
+        // a board laid out by every restart has to be checked once up front
+        State::with_board(settings.clone(), &puzzle.rows())?;
+        Ok(State::new(Settings {
+            puzzle: Some(puzzle),
+            ..settings
+        }))
+    }
+
+    // lays `rows` out at the bottom of the stage, see `with_board`
+    fn fill_stage(&mut self, rows: &[&str]) -> Result<(), String> {
+        let Vec2D { n_rows, n_cols, .. } = self.stage;
+        if rows.len() > n_rows {
+            return Err(format!("The stage has only {} rows", n_rows));
+        }
+        for (n_row, row) in (n_rows - rows.len()..).zip(rows) {
+            if row.chars().count() != n_cols {
+                return Err(format!("{:?} is not {} cells wide", row, n_cols));
+            }
+            for (n_col, cell) in row.chars().enumerate() {
+                let piece_type = match cell {
+                    '.' => PieceType::E,
+                    'X' => PieceType::G,
+                    _ => PieceType::iter()
+                        .find(|piece_type| piece_type.as_ref().starts_with(cell))
+                        .ok_or_else(|| format!("{:?} is not a cell", cell))?,
+                };
+                self.stage.data[n_row * n_cols + n_col] = piece_type;
+            }
+        }
+        Ok(())
+    }
+
+    // a puzzle game starts on the puzzle's board with all of its pieces to go
+    fn set_up_puzzle(&mut self) {
+        self.pieces_left = self.puzzle.as_ref().map(|puzzle| puzzle.pieces.len());
+        if let Some(puzzle) = self.puzzle.clone() {
+            if let Err(error) = self.fill_stage(&puzzle.rows()) {
+                error!("Can't lay out the puzzle: {}", error);
+            }
+        }
+    }
+
+    fn has_garbage(&self) -> bool {
+        self.stage.data.contains(&PieceType::G)
+    }
+
+    /// The game as JSON, in the same format it is saved to local storage in.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// Reads a game written by `to_json`, refusing one whose stage doesn't
+    /// match its dimensions or that was dealt by a custom piece source. The
+    /// falling piece is rebuilt from its type and rotation rather than trusted.
+    pub fn from_json(json: &str) -> Result<State, serde_json::Error> {
+        let state: State = serde_json::from_str(json)?;
+        State::restored(state).ok_or_else(|| serde::de::Error::custom("the game can't be resumed"))
+    }
+
+    // carries the player's display and control preferences over to another game
+    fn copy_preferences(&mut self, other: &State) {
+        self.blind_mode = other.blind_mode;
+        self.hud = other.hud;
+        self.auto_restart = other.auto_restart;
+        self.appearance = other.appearance.clone();
+        self.scaled_clear_delay = other.scaled_clear_delay;
+        self.ghost_enabled = other.ghost_enabled;
+        self.das_ms = other.das_ms;
+        self.das_cut_ms = other.das_cut_ms;
+        self.gravity_profile = other.gravity_profile;
+        self.arr_ms = other.arr_ms;
+        self.ultra_best = other.ultra_best;
+        self.key_bindings = other.key_bindings.clone();
+        self.gamepad_enabled = other.gamepad_enabled;
+        self.auto_pause = other.auto_pause;
+        self.trail_enabled = other.trail_enabled;
+        self.renderer = other.renderer;
+        self.glyphs = other.glyphs;
+        self.muted = other.muted;
+        self.script = other.script.clone();
+        self.loop_script = other.loop_script;
+        self.theme = other.theme;
+        self.countdown_secs = other.countdown_secs;
+        self.next_mode = other.next_mode;
+    }
+
+    // rebuilds what a save leaves out, None if its stage doesn't fit the game
+    // it was saved from, whatever was picked for the next one since
+    fn restored(mut state: State) -> Option<State> {
+        let Vec2D { n_rows, n_cols, .. } = state.stage;
+        let settings = state.replay.settings();
+        let scale = settings.mode.scale();
+        // a custom piece source isn't saved, its game can't go on without it
+        if settings.mode != state.mode
+            || settings.custom_pieces
+            || n_cols != settings.n_cols / scale
+            || n_rows != settings.n_rows / scale + BUFFER_ROWS + settings.safety_rows
+            || state.stage.data.len() != n_rows * n_cols
+        {
+            return None;
+        }
+        let (piece_type, mut piece_shape) = state.pieces.spawn(state.player.piece_type.clone());
+        for _ in 0..state.player.rotation % 4 {
+            piece_shape = piece_shape.rotated();
+        }
+        state.player.piece_type = piece_type;
+        state.player.piece_shape = piece_shape;
+        // neither the rng nor the piece source is saved, playing the replay
+        // again leaves them where the game did
+        let replayed = Game::from_replay(state.replay.clone());
+        state.rng = replayed.state.rng;
+        state.piece_source = replayed.state.piece_source;
+        Some(state)
+    }
+
+    // visible rows and the columns of the stage, fewer than the board's in
+    // big mode
+    fn grid_rows(&self) -> usize {
+        self.stage_rows / self.mode.scale()
+    }
+
+    fn grid_cols(&self) -> usize {
+        self.stage_cols / self.mode.scale()
+    }
+
+    // the same for the stage actually built, safety rows may already be
+    // toggled for the next game
+    fn visible_rows(&self) -> usize {
+        self.stage.n_rows - BUFFER_ROWS - self.replay.settings().safety_rows
+    }
+
+    fn board_scale(&self) -> usize {
+        (self.stage_cols / self.stage.n_cols).max(1)
+    }
+
+    // what a board cell shows, the falling piece drawn over the stage
+    fn cell(&self, row: usize, col: usize) -> &str {
+        let Position { x, y } = self.player.position;
+        let piece_shape = &self.player.piece_shape;
+        let (n_row, n_col) = (row as isize - y, col as isize - x);
+        if (0..piece_shape.n_rows as isize).contains(&n_row) && (0..piece_shape.n_cols as isize).contains(&n_col) {
+            let player_cell = piece_shape.get(n_row as usize, n_col as usize);
+            if player_cell != PieceType::E.as_ref() {
+                return player_cell;
+            }
+        }
+        self.stage.get(row, col)
+    }
+
+    // the NES profile shifts the palette with every level, as the NES did
+    fn board_class(&self) -> String {
+        let mut class = String::from("board");
+        if self.board_scale() > 1 {
+            class.push_str(" big");
+        }
+        if self.gravity_profile == GravityProfile::Nes {
+            class.push_str(&format!(" nes-{}", (self.game_status.level - 1) % 10));
+        }
+        class
+    }
+
+    // ms of play from the start to the clear that won a Sprint, the play clock
+    // at the input that made it
+    fn sprint_time(&self) -> Option<f64> {
+        if self.mode == GameMode::Sprint && self.game_status.game_won {
+            Some(self.replay.duration())
+        } else {
+            None
+        }
+    }
+
+    // ms per row of gravity at the current level
+    fn gravity(&self) -> f64 {
+        let floor = self.gravity_floor_ms as f64;
+        self.gravity_profile.duration(self.game_status.level, floor)
+    }
+
+    // ms between the rows of a held soft drop, after the one on the press
+    fn soft_drop_interval(&self) -> u64 {
+        let duration = match self.soft_drop_ms {
+            0 => self.gravity() / SOFT_DROP_FACTOR,
+            soft_drop_ms => soft_drop_ms as f64,
+        };
+        duration.max(1.0) as u64
+    }
+
+    // takes a row's worth of gravity out of `acc`, if it holds that much
+    fn take_gravity_row(&self, acc: &mut f64) -> bool {
+        let duration = self.gravity();
+        if *acc < duration {
+            return false;
+        }
+        *acc -= duration;
+        true
+    }
+
+    // holds the next row of gravity back by the DAS cut, a new charge during
+    // one starts it over rather than adding to it
+    fn cut_gravity(&self, acc: &mut f64) {
+        let cut = self.das_cut_ms as f64;
+        *acc = (*acc - cut).max(-cut);
+    }
 
-        // check if piece ouside low border of stage
-        if y + player_rows > stage_rows {
-            let distance: isize = y + player_rows - stage_rows;
-            for n_row in (player_rows - distance)..player_rows {
-                for n_col in 0..player_cols {
-                    let cell = piece.get(n_row as usize, n_col as usize);
-                    if cell != PieceType::E.as_ref() {
-                        return false;
-                    }
-                }
-            }
+    // takes effect on the next game, or right away if nothing was scored yet
+    fn set_start_level(&mut self, start_level: usize) {
+        self.start_level = start_level;
+        if self.game_status.score == 0 && self.game_status.rows_cleared == 0 {
+            self.game_status = initialize_game_status(start_level);
         }
+    }
 
-        true
+    /// Deals every piece from `piece_source`, over restarts too, until a
+    /// randomizer is picked again. Replays of its games can't be played again.
+    pub fn set_piece_source(&mut self, piece_source: Box<dyn PieceSource>) {
+        self.piece_source = piece_source;
+        self.custom_source = true;
+        self.replay.set_custom_pieces();
+    }
+
+    // pieces already in the preview queue are kept, the new randomizer deals
+    // the ones after them, from the script the game started with
+    fn set_randomizer(&mut self, randomizer: Randomizer) {
+        self.randomizer = randomizer;
+        self.custom_source = false;
+        let settings = self.replay.settings();
+        self.piece_source = randomizer.piece_source(
+            &mut self.rng,
+            &self.pieces,
+            &settings.script,
+            settings.loop_script,
+        );
+    }
+
+    fn initialize_game(&mut self) {
+        self.mode = self.next_mode;
+        // safety rows are part of the stage, so pieces can stack into them
+        // without topping out, but they are never rendered
+        self.stage = initialize_stage(
+            self.grid_rows() + BUFFER_ROWS + self.safety_rows,
+            self.grid_cols(),
+        );
+        self.game_status = initialize_game_status(self.start_level);
+        self.elapsed = 0;
+        self.played = PlayClock::default();
+        self.time_left = self.mode.time_limit();
+        self.lines_goal = self.mode.lines_goal(self.marathon_goal);
+        self.paused = false;
+        self.lock_resets = 0;
+        // every game gets its own seed for the replay, a fixed one deals the
+        // same pieces on every restart
+        let seed = self.seed.unwrap_or_else(random);
+        self.rng = StdRng::seed_from_u64(seed);
+        let settings = self.settings(seed);
+        let (script, loop_script) = settings.script();
+        if !self.custom_source {
+            self.piece_source =
+                self.randomizer.piece_source(&mut self.rng, &self.pieces, script, loop_script);
+        }
+        let safe_first_piece = self.safe_first_piece && script.is_empty();
+        self.replay = Replay::new(settings);
+        let grid_cols = self.grid_cols();
+        self.player = initialize_player(
+            self.piece_source.as_mut(),
+            &self.pieces,
+            safe_first_piece,
+            grid_cols,
+            self.safety_rows,
+        );
+        self.next_queue = initialize_queue(self.piece_source.as_mut(), self.preview_count);
+        self.droughts = initialize_droughts();
+        self.piece_counts = initialize_droughts();
+        self.held_piece = None;
+        self.held_rotation = 0;
+        self.hold_used = false;
+        self.history = UndoTree::default();
+        self.set_up_puzzle();
+        self.record_spawn();
+    }
+
+    // how long a game that just ended waits before starting over by itself
+    fn restart_delay(&self) -> Option<u64> {
+        if self.auto_restart {
+            Some(RESTART_DELAY)
+        } else {
+            None
+        }
+    }
+
+    // whether that wait, once over, starts a new game, the player may have
+    // started one by hand in the meantime
+    fn restarts_itself(&self, running: bool) -> bool {
+        self.auto_restart && self.game_status.game_over && !running
     }
 
-    fn get_completed_rows(&self) -> Vec<usize> {
-        let mut full_rows: Vec<usize> = Vec::new();
-        let Vec2D {
-            n_rows: stage_rows,
-            n_cols: stage_cols,
-            ..
-        } = self.state.stage.clone();
-        let stage_rows = stage_rows as isize;
-        let stage_cols = stage_cols as isize;
+    // height of the stack in each column, 0 for an empty column
+    fn column_heights(&self) -> Vec<usize> {
+        (0..self.stage.n_cols)
+            .map(|col| {
+                (0..self.stage.n_rows)
+                    .find(|&row| self.stage.get(row, col) != PieceType::E.as_ref())
+                    .map(|row| self.stage.n_rows - row)
+                    .unwrap_or(0)
+            })
+            .collect()
+    }
 
-        for n_row in 0..stage_rows {
-            let mut empty_cell_exists = false;
-            for n_col in 0..stage_cols {
-                if self.state.stage.get(n_row as usize, n_col as usize) == PieceType::E.as_ref() {
-                    empty_cell_exists = true;
+    // deepest single-column well as (column, depth), the depth being measured
+    // against the lower of its neighbours, walls count as neighbours of any height
+    fn deepest_well(&self) -> Option<(usize, usize)> {
+        let heights = self.column_heights();
+        (0..heights.len())
+            .filter_map(|col| {
+                let left = if col == 0 { None } else { Some(heights[col - 1]) };
+                let right = heights.get(col + 1).cloned();
+                let rim = match (left, right) {
+                    (Some(left), Some(right)) => left.min(right),
+                    (Some(side), None) | (None, Some(side)) => side,
+                    (None, None) => return None,
+                };
+                if rim > heights[col] {
+                    Some((col, rim - heights[col]))
+                } else {
+                    None
                 }
-            }
+            })
+            .max_by_key(|&(_, depth)| depth)
+    }
 
-            if !empty_cell_exists {
-                full_rows.push(n_row as usize);
+    // counts, per piece type, how many pieces spawned since it last appeared
+    // and how many spawned in all
+    fn record_spawn(&mut self) {
+        *self.piece_counts.entry(self.player.piece_type.clone()).or_insert(0) += 1;
+        for (piece, drought) in self.droughts.iter_mut() {
+            if *piece == self.player.piece_type {
+                *drought = 0;
+            } else {
+                *drought += 1;
             }
         }
+    }
 
-        full_rows
+    fn announce(&mut self, event: &str) {
+        if self.blind_mode {
+            self.announcement = announcement(event, &self.player);
+        }
     }
+}
 
-    fn is_rotate_allowed(&self) -> bool {
-        let Vec2D {
-            n_rows: player_rows,
-            n_cols: player_cols,
-            ..
-        } = self.state.player.piece_shape.clone();
-        let Position { x, y } = self.state.player.position;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
 
-        let mut rotated_data: Vec<PieceType> = Vec::new();
-        for n_col in 0..player_cols {
-            for n_row in (0..player_rows).rev() {
-                rotated_data.push(self.state.player.piece_shape.get_piece_type(n_row, n_col));
-            }
+    fn score(score: usize) -> Score {
+        Score {
+            score,
+            level: 1,
+            lines: 0,
+            name: None,
+            replay: None,
         }
-        let rotated_piece = Vec2D {
-            n_rows: player_rows,
-            n_cols: player_cols,
-            data: rotated_data,
-        };
+    }
+
+    #[test]
+    fn full_leaderboard_takes_scores_above_its_lowest() {
+        let mut leaderboard = Leaderboard::default();
+        for n in 1..=10 {
+            leaderboard.insert(score(n * 100));
+        }
+        assert!(leaderboard.qualifies(550));
+        assert!(!leaderboard.qualifies(100));
+        leaderboard.insert(score(550));
+        let scores: Vec<usize> = leaderboard.entries.iter().map(|entry| entry.score).collect();
+        assert_eq!(scores, vec![1000, 900, 800, 700, 600, 550, 500, 400, 300, 200]);
+    }
 
-        self.is_position_empty(x, y, Some(rotated_piece.clone()))
-            && self.is_player_position_valid(x, y, Some(rotated_piece.clone()))
+    #[test]
+    fn leaderboard_with_room_takes_any_score() {
+        let mut leaderboard = Leaderboard::default();
+        leaderboard.insert(score(1000));
+        assert!(leaderboard.qualifies(1));
+        assert!(!leaderboard.qualifies(0));
     }
 
-    fn is_move_allowed(&self, control: Controls, position: Option<Position>) -> bool {
-        let x: isize;
-        let y: isize;
+    // the falling piece, then the preview queue
+    fn first_pieces(state: &State) -> Vec<PieceType> {
+        let mut pieces = vec![state.player.piece_type.clone()];
+        pieces.extend(state.next_queue.iter().cloned());
+        pieces
+    }
 
-        if let Some(position) = position {
-            x = position.x;
-            y = position.y;
-        } else {
-            x = self.state.player.position.x;
-            y = self.state.player.position.y;
+    #[test]
+    fn safe_first_piece_comes_out_of_the_first_bag() {
+        for seed in 0..200 {
+            let state = State::new(Settings {
+                seed: Some(seed),
+                preview_count: 6,
+                ..Settings::default()
+            });
+            let pieces = first_pieces(&state);
+            assert!(SAFE_FIRST_PIECES.contains(&pieces[0]));
+            for piece in tetrominoes() {
+                assert_eq!(pieces.iter().filter(|&dealt| *dealt == piece).count(), 1);
+            }
         }
+    }
 
-        match control {
-            Controls::Left => {
-                if self.is_player_position_valid(x - 1, y, None)
-                    && self.is_position_empty(x - 1, y, None)
-                {
-                    true
-                } else {
-                    false
+    #[test]
+    fn queue_holds_the_preview_count_after_every_spawn() {
+        let mut game = Game::new(Settings {
+            seed: Some(4),
+            ..Settings::default()
+        });
+        for preview_count in [1, 6, 3] {
+            let next = game.state.next_queue[0].clone();
+            play(&mut game, ReplayEvent::PreviewCount(preview_count));
+            // growing or shrinking the queue keeps the piece that comes next
+            assert_eq!(game.state.next_queue[0], next);
+            for side in [Controls::Left, Controls::Right, Controls::Left, Controls::Right] {
+                assert_eq!(game.state.next_queue.len(), preview_count);
+                // against alternate walls, so the stack stays low
+                for _ in 0..5 {
+                    play(&mut game, ReplayEvent::Step(side.clone()));
                 }
+                play(&mut game, ReplayEvent::Step(Controls::Bottom));
             }
-            Controls::Right => {
-                if self.is_player_position_valid(x + 1, y, None)
-                    && self.is_position_empty(x + 1, y, None)
-                {
-                    true
-                } else {
-                    false
-                }
+            play(&mut game, ReplayEvent::Step(Controls::Hold));
+            assert_eq!(game.state.next_queue.len(), preview_count);
+        }
+        assert!(!game.is_over());
+    }
+
+    #[test]
+    fn a_burst_of_inputs_within_a_frame_plays_each_control_once() {
+        // held-key repeats pile up between two frames
+        let burst = vec![
+            Controls::Left,
+            Controls::Left,
+            Controls::Rotate,
+            Controls::Left,
+            Controls::Rotate,
+            Controls::Bottom,
+        ];
+        assert!(coalesce_inputs(burst) == vec![Controls::Left, Controls::Rotate, Controls::Bottom]);
+        // a single tap goes through as it is
+        assert!(coalesce_inputs(vec![Controls::Right]) == vec![Controls::Right]);
+        assert!(coalesce_inputs(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn mode_changes_wait_for_the_next_game() {
+        let mut state = State::with_seed(1);
+        state.next_mode = GameMode::Ultra;
+        assert_eq!(state.mode, GameMode::Marathon);
+        assert_eq!(state.time_left, None);
+        state.initialize_game();
+        assert_eq!(state.mode, GameMode::Ultra);
+        assert_eq!(state.time_left, GameMode::Ultra.time_limit());
+    }
+
+    #[test]
+    fn saved_game_round_trips_through_json() {
+        let mut state = State::with_board(
+            Settings {
+                seed: Some(3),
+                ..Settings::default()
+            },
+            &["..........", "TTT..OO...", "XXXX.XXXXX"],
+        )
+        .unwrap();
+        state.safety_rows = BEGINNER_SAFETY_ROWS;
+        state.next_mode = GameMode::Big;
+        let restored = State::from_json(&state.to_json()).unwrap();
+        assert_eq!(restored.stage.data, state.stage.data);
+        assert_eq!(restored.player.piece_type, state.player.piece_type);
+        assert_eq!(restored.next_queue, state.next_queue);
+        assert_eq!(restored.visible_rows(), STAGE_ROWS);
+    }
+
+    #[test]
+    fn big_mode_i_lying_flat_spans_eight_board_columns() {
+        let game = Game::new(Settings {
+            mode: GameMode::Big,
+            seed: Some(1),
+            ..Settings::default()
+        });
+        // turned once the I lies flat
+        let offsets = game.state.pieces.offsets(&PieceType::I, 1).to_vec();
+        let mut columns: Vec<usize> = offsets.iter().map(|(_, col)| *col).collect();
+        columns.dedup();
+        // four cells of a five column stage, each drawn two board columns wide
+        assert_eq!(game.state.stage.n_cols, 5);
+        assert_eq!(game.state.board_scale(), 2);
+        assert_eq!(columns.len() * game.state.board_scale(), 8);
+        // and it only fits the stage where all four cells are on it
+        let y = game.state.player.position.y;
+        assert!(game.state.is_player_position_valid(0, y, &offsets));
+        assert!(game.state.is_player_position_valid(1, y, &offsets));
+        assert!(!game.state.is_player_position_valid(2, y, &offsets));
+    }
+
+    #[test]
+    fn save_with_a_stage_of_other_dimensions_is_refused() {
+        let mut state = State::with_seed(3);
+        state.stage = initialize_stage(10, 10);
+        assert!(State::from_json(&state.to_json()).is_err());
+    }
+
+    // the way `Model::play` feeds the engine, minus the clock
+    fn play(game: &mut Game, event: ReplayEvent) {
+        game.state.replay.record(0.0, event.clone());
+        game.play(event);
+    }
+
+    #[test]
+    fn settings_changed_mid_game_are_replayed() {
+        let mut game = Game::new(Settings {
+            seed: Some(4),
+            ..Settings::default()
+        });
+        play(&mut game, ReplayEvent::StartLevel(5));
+        play(&mut game, ReplayEvent::Step(Controls::Bottom));
+        play(&mut game, ReplayEvent::Randomizer(Randomizer::Uniform));
+        for _ in 0..10 {
+            play(&mut game, ReplayEvent::Step(Controls::Bottom));
+        }
+        let replayed = Game::from_replay(game.state.replay.clone());
+        assert_eq!(replayed.state.stage.data, game.state.stage.data);
+        assert_eq!(first_pieces(&replayed.state), first_pieces(&game.state));
+        assert_eq!(replayed.state.game_status.level, 5);
+    }
+
+    #[test]
+    fn resumed_game_deals_the_pieces_the_saved_one_would_have() {
+        let mut game = Game::new(Settings {
+            seed: Some(8),
+            ..Settings::default()
+        });
+        for _ in 0..3 {
+            play(&mut game, ReplayEvent::Step(Controls::Bottom));
+        }
+        let mut resumed = Game {
+            state: State::from_json(&game.state.to_json()).unwrap(),
+        };
+        for _ in 0..10 {
+            play(&mut game, ReplayEvent::Step(Controls::Bottom));
+            play(&mut resumed, ReplayEvent::Step(Controls::Bottom));
+        }
+        assert_eq!(first_pieces(&resumed.state), first_pieces(&game.state));
+        assert_eq!(resumed.state.stage.data, game.state.stage.data);
+    }
+
+    #[test]
+    fn malformed_piece_shapes_are_refused() {
+        let mut cells = vec![PieceType::E; 4];
+        cells[1] = PieceType::T;
+        assert!(Piece::new(Vec2D::new(3, 3, cells.clone())).is_err());
+        assert!(Piece::new(Vec2D::new(1, 4, cells.clone())).is_err());
+        assert!(Piece::new(Vec2D::new(2, 2, cells)).is_ok());
+    }
+
+    // rows of gravity over `ticks` GRAVITY_STEP intervals, the way Msg::Tick
+    // counts them
+    fn rows_fallen(state: &State, ticks: usize) -> usize {
+        let mut acc = 0.0;
+        let mut rows = 0;
+        for _ in 0..ticks {
+            acc += GRAVITY_STEP as f64;
+            while state.take_gravity_row(&mut acc) {
+                rows += 1;
             }
-            Controls::Bottom | Controls::Down => {
-                if self.is_player_position_valid(x, y + 1, None)
-                    && self.is_position_empty(x, y + 1, None)
-                {
-                    true
-                } else {
-                    false
-                }
+        }
+        rows
+    }
+
+    #[test]
+    fn ticks_drop_rows_at_the_level_gravity() {
+        let mut state = State::with_seed(2);
+        for level in [1, 5, 10, 15] {
+            state.game_status.level = level;
+            let ticks = 1000;
+            let expected = (ticks as f64 * GRAVITY_STEP as f64 / state.gravity()) as usize;
+            assert_eq!(rows_fallen(&state, ticks), expected);
+        }
+    }
+
+    #[test]
+    fn gravity_speeds_up_every_level_until_the_floor() {
+        let mut state = State::with_seed(2);
+        let floor = state.gravity_floor_ms as f64;
+        let mut last = f64::INFINITY;
+        for level in 1..=20 {
+            state.game_status.level = level;
+            let gravity = state.gravity();
+            assert!(gravity < last || gravity == floor, "level {} is no faster", level);
+            last = gravity;
+        }
+        assert_eq!(last, floor);
+    }
+
+    #[test]
+    fn every_bag_deals_each_tetromino_once() {
+        let mut source = BagPieceSource::new(StdRng::seed_from_u64(5));
+        let mut dealt = vec![source.first()];
+        dealt.extend((1..7 * 20).map(|_| source.next()));
+        for bag in dealt.chunks(7) {
+            for piece in tetrominoes() {
+                assert_eq!(bag.iter().filter(|&dealt| *dealt == piece).count(), 1);
             }
-            Controls::Rotate => {
-                if self.is_rotate_allowed() {
-                    true
-                } else {
-                    false
-                }
+        }
+    }
+
+    #[test]
+    fn gravity_never_slows_down_or_reaches_zero() {
+        for profile in [GravityProfile::Guideline, GravityProfile::Nes] {
+            let mut last = f64::INFINITY;
+            for level in 1..=30 {
+                let duration = profile.duration(level, 0.0);
+                assert!(duration <= last, "level {} is slower", level);
+                assert!(duration >= MIN_DURATION);
+                last = duration;
             }
-            Controls::Pause => todo!(),
         }
     }
-}
 
-#[derive(EnumIter, ToString, Clone, PartialEq, Serialize, Deserialize)]
-pub enum Filter {
-    All,
-    Active,
-    Completed,
-}
+    #[test]
+    fn nes_gravity_turns_frames_into_milliseconds() {
+        // (level here, NES frames a row), the NES counting levels from 0
+        let table = [(1, 48), (2, 43), (9, 8), (10, 6), (14, 4), (19, 3), (29, 2), (30, 1)];
+        for (level, frames) in table {
+            let duration = GravityProfile::Nes.duration(level, 0.0);
+            assert_eq!(nes_frames(level), frames);
+            assert!((duration - frames as f64 * 1000.0 / 60.0988).abs() < 1e-9);
+        }
+        assert!((GravityProfile::Nes.duration(1, 0.0) - 798.7).abs() < 0.1);
+        assert!((GravityProfile::Nes.duration(30, 0.0) - 16.64).abs() < 0.01);
+        // the floor only holds back the guideline curve
+        let floor = GRAVITY_FLOOR_DEFAULT as f64 * 10.0;
+        assert_eq!(GravityProfile::Nes.duration(10, floor), GravityProfile::Nes.duration(10, 0.0));
+        assert_eq!(GravityProfile::Guideline.duration(10, floor), floor);
+    }
 
-impl<'a> Into<Href> for &'a Filter {
-    fn into(self) -> Href {
-        match *self {
-            Filter::All => "#/".into(),
-            Filter::Active => "#/active".into(),
-            Filter::Completed => "#/completed".into(),
+    #[test]
+    fn gravity_in_cells_per_second_follows_the_level() {
+        // (level, guideline cells a second)
+        for (level, cells) in [(1, 1.0), (2, 1.26), (5, 2.82), (10, 15.6)] {
+            let per_second = cells_per_second(get_duration(level, 0.0));
+            assert!((per_second - cells).abs() < 0.05, "level {}: {}", level, per_second);
         }
+        assert_eq!(cells_per_second(250.0), 4.0);
+        // the floor caps it
+        assert_eq!(cells_per_second(get_duration(20, 100.0)), 10.0);
     }
-}
 
-impl Filter {
-    fn fit(&self, entry: &Entry) -> bool {
-        match *self {
-            Filter::All => true,
-            Filter::Active => !entry.completed,
-            Filter::Completed => entry.completed,
+    #[test]
+    fn scaled_clear_delay_holds_a_tetris_longer_than_a_single() {
+        assert!(last_clear_duration(4, true) > last_clear_duration(1, true));
+        assert_eq!(last_clear_duration(1, true), LAST_CLEAR_DURATION);
+        assert_eq!(last_clear_duration(4, true), LAST_CLEAR_DURATION + 3 * LAST_CLEAR_STEP);
+        // flat by default
+        for rows_cleared in 1..=4 {
+            assert_eq!(last_clear_duration(rows_cleared, false), LAST_CLEAR_DURATION);
         }
     }
-}
 
-impl State {
-    fn initialize_game(&mut self) {
-        self.stage = initialize_stage(21, 10);
-        self.game_status = initialize_game_status();
-    }
-
-    fn add_player_piece_stage(&mut self) {
-        let Vec2D {
-            n_rows: stage_rows,
-            n_cols: stage_cols,
-            ..
-        } = self.stage.clone();
-        let Vec2D {
-            n_rows: player_rows,
-            n_cols: player_cols,
-            ..
-        } = self.player.piece_shape.clone();
-        let Position { x, y } = self.player.position;
-        let player_rows = player_rows as isize;
-        let player_cols = player_cols as isize;
-        let stage_rows = stage_rows as isize;
-        let stage_cols = stage_cols as isize;
-
-        for n_row in 0..player_rows {
-            for n_col in 0..player_cols {
-                let row = n_row + y;
-                let col = n_col + x;
-
-                if row < 0 || row > stage_rows || col < 0 || col > stage_cols {
-                    info!("nope");
-                } else {
-                    let cell = self.player.piece_shape.get(n_row as usize, n_col as usize);
-                    if cell != PieceType::E.as_ref() {
-                        self.stage.set(row as usize, col as usize, cell);
-                    }
-                }
-            }
+    #[test]
+    fn cell_kinds_round_trip_through_json() {
+        for piece_type in PieceType::iter() {
+            let json = serde_json::to_string(&piece_type).unwrap();
+            assert_eq!(serde_json::from_str::<PieceType>(&json).unwrap(), piece_type);
         }
-        let mut random_piece: PieceType;
-        loop {
-            random_piece = get_random_piece();
-            if random_piece != self.player.piece_type {
-                break;
-            }
+        // older saves may still hold the unused cell kind
+        assert_eq!(serde_json::from_str::<PieceType>("\"TMP\"").unwrap(), PieceType::E);
+    }
+
+    #[test]
+    fn a_bag_of_spawns_counts_each_tetromino_once_more() {
+        let mut game = Game::new(Settings {
+            seed: Some(6),
+            ..Settings::default()
+        });
+        let spawn = |game: &mut Game| {
+            game.step(Controls::Bottom);
+            // an empty stage every time, so the drops never top out
+            game.state.stage.data.iter_mut().for_each(|cell| *cell = PieceType::E);
+        };
+        // the first piece came with the game, six more finish its bag
+        for _ in 0..6 {
+            spawn(&mut game);
+        }
+        let counts = game.state.piece_counts.clone();
+        for _ in 0..7 {
+            spawn(&mut game);
+        }
+        for piece in tetrominoes() {
+            assert_eq!(counts[&piece], 1);
+            assert_eq!(game.state.piece_counts[&piece], counts[&piece] + 1);
+        }
+    }
+
+    #[test]
+    fn gravity_past_the_last_level_stays_at_the_floor() {
+        assert_eq!(get_duration(99, GRAVITY_FLOOR_DEFAULT as f64), GRAVITY_FLOOR_DEFAULT as f64);
+        assert_eq!(get_duration(99, 0.0), MIN_DURATION);
+    }
+
+    #[test]
+    fn only_the_tetrominoes_empty_and_garbage_are_cells() {
+        let kinds: Vec<String> = PieceType::iter().map(|kind| kind.as_ref().to_string()).collect();
+        assert_eq!(kinds, vec!["E", "I", "J", "L", "T", "O", "S", "Z", "G"]);
+    }
+
+    #[test]
+    fn minimal_hud_keeps_the_preview_and_hold() {
+        for section in [HudSection::Preview, HudSection::Hold] {
+            assert!(HudMode::Full.shows(section));
+            assert!(HudMode::Minimal.shows(section));
+            assert!(!HudMode::None.shows(section));
+        }
+    }
+
+    #[test]
+    fn only_the_full_hud_shows_the_leaderboard() {
+        assert!(HudMode::Full.shows(HudSection::Leaderboard));
+        assert!(!HudMode::Minimal.shows(HudSection::Leaderboard));
+        assert!(!HudMode::None.shows(HudSection::Leaderboard));
+    }
+
+    fn scripted(loop_script: bool) -> State {
+        State::new(Settings {
+            seed: Some(12),
+            script: vec![PieceType::T, PieceType::S, PieceType::I],
+            loop_script,
+            ..Settings::default()
+        })
+    }
+
+    #[test]
+    fn i_drought_grows_with_every_other_piece_and_ends_with_an_i() {
+        let mut game = Game::new(Settings {
+            seed: Some(12),
+            script: vec![PieceType::O, PieceType::S, PieceType::Z, PieceType::I, PieceType::T],
+            loop_script: true,
+            ..Settings::default()
+        });
+        let i_drought = |game: &Game| game.state.droughts[&PieceType::I];
+        for (piece_type, drought) in [
+            (PieceType::O, 1),
+            (PieceType::S, 2),
+            (PieceType::Z, 3),
+            (PieceType::I, 0),
+            (PieceType::T, 1),
+        ] {
+            assert_eq!(game.state.player.piece_type, piece_type);
+            assert_eq!(i_drought(&game), drought);
+            play(&mut game, ReplayEvent::Step(Controls::Bottom));
         }
-        let piece_shape = PIECES.get(random_piece.as_ref()).unwrap().shape.clone();
-        self.player.piece_type = random_piece;
-        self.player.piece_shape = piece_shape;
-        self.player.position.x = 4;
-        self.player.position.y = 0;
+        assert_eq!(i_drought(&game), 2);
+        // a new game counts from its own first piece
+        game.state.initialize_game();
+        assert_eq!(i_drought(&game), 1);
+    }
+
+    #[test]
+    fn auto_restart_starts_a_fresh_game_after_the_delay() {
+        let mut game = Game::new(Settings::default());
+        assert_eq!(game.state.restart_delay(), None);
+        game.state.auto_restart = true;
+        while !game.is_over() {
+            play(&mut game, ReplayEvent::Step(Controls::Bottom));
+        }
+        assert_eq!(game.state.restart_delay(), Some(RESTART_DELAY));
+        // a game already started by hand isn't thrown away
+        assert!(!game.state.restarts_itself(true));
+        assert!(game.state.restarts_itself(false));
+
+        // what the restart then does, dealt from a new seed
+        let seed = game.state.replay.settings().seed;
+        game.state.initialize_game();
+        assert!(!game.is_over());
+        assert!(game.state.stage.data.iter().all(|cell| *cell == PieceType::E));
+        assert_ne!(game.state.replay.settings().seed, seed);
+        assert!(!game.state.restarts_itself(false));
     }
 
-    fn update_game_state(&mut self, rows_cleared: usize) {
-        if rows_cleared > 0 {
-            let score: usize = match rows_cleared {
-                1 => 40 * self.game_status.level,
-                2 => 100 * self.game_status.level,
-                3 => 300 * self.game_status.level,
-                _ => 1200 * self.game_status.level,
+    #[test]
+    fn appearance_is_handed_to_the_stylesheet_as_custom_properties() {
+        let appearance = Appearance {
+            cell_gap: 3,
+            cell_radius: 6,
+            background: "#123456".into(),
+        };
+        assert_eq!(
+            appearance.style(),
+            "--cell-gap: 3px; --cell-radius: 6px; --board-background: #123456;"
+        );
+        assert!(Appearance::default().style().starts_with("--cell-gap: 2px;"));
+    }
+
+    #[test]
+    fn four_deep_well_by_the_right_wall_is_the_deepest() {
+        let board = |rows: &[&str]| {
+            let settings = Settings {
+                seed: Some(1),
+                ..Settings::default()
             };
-            let rows_cleared = self.game_status.rows_cleared + rows_cleared;
-            let level: usize = (rows_cleared / 10) + 1;
-            self.game_status = GameStatus {
-                level,
-                score: self.game_status.score + score,
-                rows_cleared,
-                game_over: self.game_status.game_over,
-            }
+            State::with_board(settings, rows).unwrap()
+        };
+        let state = board(&["X.XXXXXXX.", "X.XXXXXXX.", "XXXXXXXXX.", "XXXXXXXXX."]);
+        // the two deep gap in column 1 is the shallower one
+        assert_eq!(state.deepest_well(), Some((9, 4)));
+        assert_eq!(board(&[]).deepest_well(), None);
+    }
+
+    #[test]
+    fn looping_script_deals_its_cycle_again_and_again() {
+        let mut state = scripted(true);
+        let mut dealt = first_pieces(&state);
+        dealt.extend((0..8).map(|_| state.piece_source.next()));
+        assert_eq!(dealt.len(), 12);
+        for cycle in dealt.chunks(3) {
+            assert_eq!(cycle, &[PieceType::T, PieceType::S, PieceType::I]);
         }
     }
 
-    fn remove_rows(&mut self, rows: Vec<usize>) {
-        let Vec2D {
-            n_cols: stage_cols, ..
-        } = self.stage.clone();
-        let stage_cols = stage_cols as isize;
+    #[test]
+    fn script_hands_over_to_the_randomizer_once_it_runs_out() {
+        let mut state = scripted(false);
+        let mut dealt = first_pieces(&state);
+        // the script, then one whole bag
+        dealt.extend((dealt.len()..10).map(|_| state.piece_source.next()));
+        assert_eq!(&dealt[..3], &[PieceType::T, PieceType::S, PieceType::I]);
+        for piece in tetrominoes() {
+            assert_eq!(dealt[3..].iter().filter(|&dealt| *dealt == piece).count(), 1);
+        }
+    }
 
-        for n_row in rows.clone() {
-            let stage = self.stage.clone();
-            for n_col in 0..stage_cols {
-                for row in 0..n_row + 1 {
-                    let piece = if row == 0 {
-                        PieceType::E.as_ref()
-                    } else {
-                        stage.get(row - 1, n_col as usize).clone().as_ref()
-                    };
-                    self.stage.set(row, n_col as usize, piece);
-                }
+    // ms of GRAVITY_STEP ticks until the first row falls, from `acc`
+    fn ms_to_next_row(state: &State, mut acc: f64) -> u64 {
+        let mut ms = 0;
+        while !state.take_gravity_row(&mut acc) {
+            acc += GRAVITY_STEP as f64;
+            ms += GRAVITY_STEP;
+        }
+        ms
+    }
+
+    #[test]
+    fn das_cut_holds_back_the_next_row() {
+        let mut state = State::with_seed(2);
+        let mut acc = 0.0;
+        state.cut_gravity(&mut acc);
+        let uncut = ms_to_next_row(&state, acc);
+
+        state.das_cut_ms = 200;
+        let mut acc = 0.0;
+        state.cut_gravity(&mut acc);
+        let cut = ms_to_next_row(&state, acc);
+        assert!(cut + GRAVITY_STEP >= uncut + 200 && cut <= uncut + 200 + GRAVITY_STEP);
+        // a second charge during the cut starts it over
+        state.cut_gravity(&mut acc);
+        assert_eq!(acc, -200.0);
+    }
+
+    #[test]
+    fn fortieth_line_wins_a_sprint_and_keeps_its_time() {
+        let settings = Settings {
+            seed: Some(1),
+            mode: GameMode::Sprint,
+            script: vec![PieceType::I],
+            ..Settings::default()
+        };
+        let state = State::with_board(settings, &["XXXXXXXXX.", "XXXXXXXXX."]).unwrap();
+        let mut game = Game { state };
+        game.state.game_status.rows_cleared = SPRINT_LINES - 2;
+        // the I spawns upright in the second column of its grid
+        game.state.player.position.x = 8;
+        game.state.replay.record(83_456.0, ReplayEvent::Step(Controls::Bottom));
+        assert_eq!(game.play(ReplayEvent::Step(Controls::Bottom)), StepOutcome::GameOver);
+        assert!(game.state.game_status.game_won);
+        assert_eq!(game.state.sprint_time(), Some(83_456.0));
+
+        let mut sprint_times = SprintTimes::default();
+        sprint_times.insert(90_000.0);
+        assert_eq!(sprint_times.insert(83_456.0), Some(1));
+        assert_eq!(format_time(83_456.0), "1:23.456");
+    }
+
+    #[test]
+    fn finished_game_replays_to_its_final_score() {
+        let mut game = Game::new(Settings {
+            seed: Some(13),
+            ..Settings::default()
+        });
+        let mut at = 0.0;
+        while !game.is_over() {
+            at += 250.0;
+            game.state.replay.record(at, ReplayEvent::Step(Controls::Bottom));
+            game.play(ReplayEvent::Step(Controls::Bottom));
+        }
+        let score = game.state.game_status.score;
+        assert!(score > 0);
+        assert_eq!(Game::from_replay(game.state.replay.clone()).state.game_status.score, score);
+
+        let (mut playback, mut watched) = Playback::new(game.state.replay.clone());
+        assert!(!playback.advance(&mut watched, 100.0));
+        assert!(playback.advance(&mut watched, 1000.0));
+        assert!(!watched.is_over());
+        while !playback.is_finished() {
+            at += 1000.0;
+            playback.advance(&mut watched, at);
+        }
+        assert!(watched.is_over());
+        assert_eq!(watched.state.game_status.score, score);
+        assert_eq!(watched.state.stage.data, game.state.stage.data);
+    }
+
+    #[test]
+    fn scores_are_checked_against_their_replay() {
+        let mut game = Game::new(Settings {
+            seed: Some(14),
+            ..Settings::default()
+        });
+        for _ in 0..5 {
+            play(&mut game, ReplayEvent::Step(Controls::Bottom));
+        }
+        let claim = |points| Score {
+            replay: Some(game.state.replay.clone()),
+            ..score(points)
+        };
+        let points = game.state.game_status.score;
+        assert_eq!(verify_replay(&game.state.replay), Some(points));
+
+        let mut leaderboard = Leaderboard::default();
+        assert!(leaderboard.submit(claim(points + 100)).is_err());
+        assert!(leaderboard.submit(score(points)).is_err());
+        assert!(leaderboard.entries.is_empty());
+        assert!(leaderboard.submit(claim(points)).is_ok());
+        assert_eq!(leaderboard.entries.len(), 1);
+    }
+
+    #[test]
+    fn cached_offsets_match_a_scan_of_the_shape() {
+        let pieces = PieceSet::default();
+        for piece_type in tetrominoes() {
+            let mut shape = pieces.get(&piece_type).unwrap();
+            for rotation in 0..4 {
+                let scanned: Vec<(usize, usize)> = shape
+                    .data
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, cell)| **cell != PieceType::E)
+                    .map(|(index, _)| (index / shape.n_cols, index % shape.n_cols))
+                    .collect();
+                assert_eq!(pieces.offsets(&piece_type, rotation), &scanned[..]);
+                shape = shape.rotated();
             }
         }
     }
 
-    fn game_over(&mut self) {
-        self.game_status.game_over = true;
+    #[test]
+    fn four_counterclockwise_turns_come_back_to_the_start() {
+        let pieces = PieceSet::default();
+        for piece_type in tetrominoes() {
+            let shape = pieces.get(&piece_type).unwrap();
+            let mut turned = shape.clone();
+            for _ in 0..4 {
+                turned = turned.rotated_ccw();
+            }
+            assert_eq!(turned.data, shape.data);
+            // and one of them is three clockwise turns
+            assert_eq!(shape.rotated_ccw().data, shape.rotated().rotated().rotated().data);
+        }
     }
 
-    fn rotate_player_piece(&mut self) {
-        let Vec2D {
-            n_rows: player_rows,
-            n_cols: player_cols,
-            ..
-        } = self.player.piece_shape.clone();
+    #[test]
+    fn sprint_time_leaves_out_the_countdown_and_pauses() {
+        let settings = Settings {
+            seed: Some(1),
+            mode: GameMode::Sprint,
+            script: vec![PieceType::I],
+            ..Settings::default()
+        };
+        let state = State::with_board(settings, &["XXXXXXXXX.", "XXXXXXXXX."]).unwrap();
+        let mut game = Game { state };
+        game.state.game_status.rows_cleared = SPRINT_LINES - 2;
+        game.state.player.position.x = 8;
+        // set up at 0 s, started after a 3 s countdown, paused from 5 s to 9 s
+        // and won at 10 s
+        game.state.played.start(3_000.0);
+        game.state.played.stop(5_000.0);
+        game.state.played.start(9_000.0);
+        let at = game.state.played.at(10_000.0);
+        game.state.replay.record(at, ReplayEvent::Step(Controls::Bottom));
+        assert_eq!(game.play(ReplayEvent::Step(Controls::Bottom)), StepOutcome::GameOver);
+        assert_eq!(game.state.sprint_time(), Some(3_000.0));
+        // the clock stops with the game, so the timer shows the same time
+        game.state.played.stop(10_000.0);
+        assert_eq!(game.state.played.at(60_000.0), 3_000.0);
+    }
 
-        let mut rotated_data: Vec<PieceType> = Vec::new();
-        for n_col in 0..player_cols {
-            for n_row in (0..player_rows).rev() {
-                rotated_data.push(self.player.piece_shape.get_piece_type(n_row, n_col));
+    #[test]
+    fn ultra_clock_keeps_the_part_of_a_second_played_before_a_pause() {
+        let mut game = Game::new(Settings {
+            seed: Some(1),
+            mode: GameMode::Ultra,
+            ..Settings::default()
+        });
+        // paused after 900 ms of play three times over, which used to count
+        // no second at all
+        for start in [0.0, 1_000.0, 2_000.0] {
+            game.state.played.start(start);
+            game.state.played.stop(start + 900.0);
+        }
+        // a Second for each one the clock ran past, like Model::count_seconds
+        while game.state.elapsed < game.state.played.seconds(3_000.0) {
+            game.play(ReplayEvent::Second);
+        }
+        assert_eq!(game.state.elapsed, 2);
+        assert_eq!(game.state.time_left, Some(ULTRA_SECONDS - 2));
+    }
+
+    #[test]
+    fn ultra_time_left_holds_still_while_paused() {
+        let mut game = Game::new(Settings {
+            seed: Some(1),
+            mode: GameMode::Ultra,
+            ..Settings::default()
+        });
+        let count_seconds = |game: &mut Game, now: f64| {
+            while game.state.elapsed < game.state.played.seconds(now) {
+                assert_eq!(game.play(ReplayEvent::Second), StepOutcome::Moved);
             }
+        };
+        game.state.played.start(0.0);
+        count_seconds(&mut game, 30_500.0);
+        assert_eq!(game.play(ReplayEvent::Step(Controls::Pause)), StepOutcome::Paused);
+        game.state.played.stop(30_500.0);
+        // a minute away from the game costs no time at all
+        assert_eq!(game.state.played.seconds(90_500.0), 30);
+        assert_eq!(game.play(ReplayEvent::Second), StepOutcome::Blocked);
+        assert_eq!(game.state.time_left, Some(ULTRA_SECONDS - 30));
+        assert_eq!(game.play(ReplayEvent::Step(Controls::Pause)), StepOutcome::Resumed);
+        game.state.played.start(90_500.0);
+        count_seconds(&mut game, 91_000.0);
+        assert_eq!(game.state.time_left, Some(ULTRA_SECONDS - 31));
+    }
+
+    // counts the tasks dropped, which is what cancels a yew timer
+    struct Counted(Rc<Cell<usize>>);
+
+    impl Task for Counted {
+        fn is_active(&self) -> bool {
+            true
+        }
+
+        fn cancel(&mut self) {}
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn destroying_the_model_stops_every_timer() {
+        let dropped = Rc::new(Cell::new(0));
+        let task = || -> Option<Box<dyn Task>> { Some(Box::new(Counted(dropped.clone()))) };
+        let mut jobs = Jobs {
+            gravity: task(),
+            clear: task(),
+            trail: task(),
+            restart: task(),
+            playback: task(),
+            lock: task(),
+            shift: task(),
+            soft_drop: task(),
+            countdown: task(),
+            gamepad: task(),
+            confirm_restart: task(),
+            versus: task(),
+        };
+        // ending a game leaves the gamepad polling, a replay, a pending restart
+        // and a local versus match
+        jobs.stop_game();
+        assert_eq!(dropped.get(), 8);
+        assert!(jobs.gamepad.is_some() && jobs.playback.is_some() && jobs.versus.is_some());
+        jobs.stop_all();
+        assert_eq!(dropped.get(), 12);
+    }
+
+    struct OnlyO;
+
+    impl PieceSource for OnlyO {
+        fn next(&mut self) -> PieceType {
+            PieceType::O
+        }
+    }
+
+    #[test]
+    fn a_piece_missing_from_the_set_spawns_as_an_o() {
+        let only_i: HashMap<String, Piece> = PIECES
+            .iter()
+            .filter(|(name, _)| **name == "I")
+            .map(|(name, piece)| (name.to_string(), piece.clone()))
+            .collect();
+        let pieces = PieceSet::new(only_i).unwrap();
+        assert!(pieces.get(&PieceType::T).is_none());
+        let (piece_type, shape) = pieces.spawn(PieceType::T);
+        assert_eq!(piece_type, PieceType::O);
+        assert_eq!(shape.data, FALLBACK_PIECE.shape.data);
+
+        // a source dealing pieces the set lacks still plays
+        let mut state = State::with_pieces(
+            Settings {
+                seed: Some(1),
+                ..Settings::default()
+            },
+            pieces,
+        );
+        state.set_piece_source(Box::new(OnlyO));
+        state.initialize_game();
+        let mut game = Game { state };
+        for _ in 0..3 {
+            assert_eq!(game.state.player.piece_type, PieceType::O);
+            play(&mut game, ReplayEvent::Step(Controls::Bottom));
+        }
+        assert!(!game.state.game_status.game_over);
+    }
+
+    #[test]
+    fn zero_arr_shifts_the_piece_to_the_wall_at_once() {
+        let mut game = Game::new(Settings {
+            seed: Some(1),
+            ..Settings::default()
+        });
+        game.state.set_piece_source(Box::new(OnlyO));
+        game.state.initialize_game();
+        let columns = |game: &Game| -> Vec<isize> {
+            let player = &game.state.player;
+            let occupied = player.piece_shape.occupied();
+            occupied.iter().map(|(_, col)| player.position.x + *col as isize).collect()
+        };
+        let start = *columns(&game).iter().max().unwrap();
+        let mut steps = 0;
+        shift_to_wall(Controls::Right, |event| {
+            steps += 1;
+            game.play(event)
+        });
+        // a step for each column to the wall, and the one that's blocked
+        assert_eq!(columns(&game).iter().max(), Some(&9));
+        assert_eq!(steps, 9 - start + 1);
+
+        shift_to_wall(Controls::Left, |event| game.play(event));
+        assert_eq!(columns(&game).iter().min(), Some(&0));
+    }
+
+    #[test]
+    fn first_and_later_pieces_spawn_on_the_same_rows() {
+        let mut game = Game::new(Settings {
+            seed: Some(1),
+            ..Settings::default()
+        });
+        game.state.set_piece_source(Box::new(OnlyO));
+        game.state.initialize_game();
+        let cells = |game: &Game| -> Vec<(isize, isize)> {
+            let Position { x, y } = game.state.player.position;
+            let occupied = game.state.player.piece_shape.occupied();
+            occupied.iter().map(|(row, col)| (y + *row as isize, x + *col as isize)).collect()
+        };
+        // the O's top row in the hidden buffer row, its bottom one on the
+        // stage's first visible row, in the middle two columns
+        let spawned = vec![(0, 4), (0, 5), (1, 4), (1, 5)];
+        assert_eq!(cells(&game), spawned);
+        play(&mut game, ReplayEvent::Step(Controls::Bottom));
+        assert_eq!(cells(&game), spawned);
+        play(&mut game, ReplayEvent::Step(Controls::Hold));
+        assert_eq!(cells(&game), spawned);
+    }
+
+    #[test]
+    fn shared_game_link_reads_back_the_settings_it_was_made_from() {
+        let settings = Settings {
+            seed: Some(42),
+            start_level: 7,
+            mode: GameMode::Sprint,
+            rotation_system: RotationSystem::Simple,
+            puzzle: Some(Puzzle::parse("XXXX..XXXX\nOIT").unwrap()),
+            ..Settings::default()
+        };
+        let fragment = share_fragment(&settings);
+        assert!(fragment.starts_with("#game="));
+        assert!(!fragment.contains(['"', ' ', '{']));
+
+        let shared = settings_from_fragment(&fragment).unwrap();
+        let json = |settings: &Settings| serde_json::to_string(settings).unwrap();
+        assert_eq!(json(&shared), json(&settings));
+        // the same game, piece for piece
+        assert_eq!(first_pieces(&State::new(shared)), first_pieces(&State::new(settings)));
+    }
+
+    #[test]
+    fn malformed_shared_game_links_are_ignored() {
+        let shared = |settings: Settings| settings_from_fragment(&share_fragment(&settings));
+        assert!(settings_from_fragment("").is_none());
+        assert!(settings_from_fragment("#seed=42").is_none());
+        assert!(settings_from_fragment("#game=%7B%22seed").is_none());
+        assert!(settings_from_fragment("#game=%7").is_none());
+        assert!(settings_from_fragment("#game=%ZZ").is_none());
+        assert!(shared(Settings { n_cols: 4000, ..Settings::default() }).is_none());
+        assert!(shared(Settings { preview_count: 0, ..Settings::default() }).is_none());
+        assert!(shared(Settings { custom_pieces: true, ..Settings::default() }).is_none());
+        let puzzle = Puzzle::parse("..........\nO").unwrap();
+        assert!(shared(Settings { puzzle: Some(puzzle), ..Settings::default() }).is_none());
+        assert!(shared(Settings::default()).is_some());
+    }
+
+    #[test]
+    fn versus_keys_play_their_own_board() {
+        assert!(versus_control("ArrowLeft") == Some((0, Controls::Left)));
+        assert!(versus_control("a") == Some((1, Controls::Left)));
+        assert!(versus_control(" ") == Some((1, Controls::Bottom)));
+        assert!(versus_control("Enter") == Some((0, Controls::Bottom)));
+        assert!(versus_control("x").is_none());
+
+        let mut versus = Versus::new(Settings::default());
+        let (player, control) = versus_control("d").unwrap();
+        versus.play(player, ReplayEvent::Step(control));
+        let x = |game: &Game| game.state.player.position.x;
+        assert_eq!(x(&versus.games[1]), x(&versus.games[0]) + 1);
+    }
+
+    // drops each of the puzzle's pieces flat against the right wall
+    fn play_puzzle(text: &str) -> Game {
+        let settings = Settings {
+            seed: Some(2),
+            ..Settings::default()
+        };
+        let puzzle = Puzzle::parse(text).unwrap();
+        let mut game = Game {
+            state: State::with_puzzle(settings, puzzle).unwrap(),
+        };
+        while !game.is_over() {
+            play(&mut game, ReplayEvent::Step(Controls::Rotate));
+            shift_to_wall(Controls::Right, |event| {
+                game.state.replay.record(0.0, event.clone());
+                game.play(event)
+            });
+            play(&mut game, ReplayEvent::Step(Controls::Bottom));
+        }
+        game
+    }
+
+    #[test]
+    fn puzzle_cleared_within_its_pieces_is_won() {
+        let game = play_puzzle("XXXXXX....\nXXXXXX....\nXXXXXX....\nIII");
+        assert!(game.state.game_status.game_won);
+        assert_eq!(game.state.game_status.rows_cleared, 3);
+        assert_eq!(game.state.pieces_left, Some(0));
+        assert!(game.state.stage.data.iter().all(|cell| *cell == PieceType::E));
+        // the replay lays the same board out again
+        let points = game.state.game_status.score;
+        assert_eq!(verify_replay(&game.state.replay), Some(points));
+    }
+
+    #[test]
+    fn puzzle_left_unsolved_when_its_pieces_run_out_is_lost() {
+        let mut game = play_puzzle("XXXXXX....\nXXXXXX....\nXXXXXX....\nIIO");
+        assert!(game.is_over());
+        assert!(!game.state.game_status.game_won);
+        assert_eq!(game.state.game_status.rows_cleared, 2);
+        assert_eq!(game.state.pieces_left, Some(0));
+
+        // a restart sets the puzzle up again, until it's left
+        game.state.initialize_game();
+        assert_eq!(game.state.pieces_left, Some(3));
+        assert_eq!(rows_of_garbage(&game.state), 3);
+        game.state.puzzle = None;
+        game.state.initialize_game();
+        assert_eq!(game.state.pieces_left, None);
+        assert_eq!(rows_of_garbage(&game.state), 0);
+    }
+
+    fn rows_of_garbage(state: &State) -> usize {
+        let n_cols = state.stage.n_cols;
+        state.stage.data.chunks(n_cols).filter(|row| row.contains(&PieceType::G)).count()
+    }
+
+    #[test]
+    fn puzzles_need_a_board_with_garbage_and_pieces() {
+        assert!(Puzzle::parse("XXXX..XXXX").is_err());
+        assert!(Puzzle::parse("O").is_err());
+        let puzzle = |text| Puzzle::parse(text).unwrap();
+        assert!(State::with_puzzle(Settings::default(), puzzle("..........\nO")).is_err());
+        assert!(State::with_puzzle(Settings::default(), puzzle("XXXX..XXX\nO")).is_err());
+        assert!(State::with_puzzle(Settings::default(), puzzle("XXXX..XXXX\nO")).is_ok());
+    }
+
+    #[test]
+    fn custom_piece_source_deals_every_piece_over_restarts() {
+        let mut game = Game::new(Settings {
+            seed: Some(1),
+            ..Settings::default()
+        });
+        game.state.set_piece_source(Box::new(OnlyO));
+        game.state.initialize_game();
+        for _ in 0..3 {
+            assert!(first_pieces(&game.state).iter().all(|piece_type| *piece_type == PieceType::O));
+            play(&mut game, ReplayEvent::Step(Controls::Bottom));
         }
-        self.player.piece_shape.data = rotated_data;
+        game.state.initialize_game();
+        assert!(first_pieces(&game.state).iter().all(|piece_type| *piece_type == PieceType::O));
+
+        // a replay would deal the randomizer's pieces instead
+        assert!(!game.state.replay.can_replay());
+        assert_eq!(verify_replay(&game.state.replay), None);
+        assert!(State::from_json(&game.state.to_json()).is_err());
+
+        game.state.set_randomizer(Randomizer::Bag);
+        game.state.initialize_game();
+        assert!(game.state.replay.can_replay());
+        assert!(first_pieces(&game.state).iter().any(|piece_type| *piece_type != PieceType::O));
+    }
+
+    #[test]
+    fn gravity_floor_and_soft_drop_rate_are_replayed() {
+        let mut game = Game::new(Settings {
+            seed: Some(3),
+            gravity_floor_ms: 100,
+            ..Settings::default()
+        });
+        assert_eq!(game.state.gravity_floor_ms, 100);
+        let replay: Replay = serde_json::from_str(&game.state.export_replay()).unwrap();
+        assert_eq!(replay.settings().gravity_floor_ms, 100);
+        assert_eq!(Game::from_replay(replay).state.gravity_floor_ms, 100);
+
+        play(&mut game, ReplayEvent::Step(Controls::Bottom));
+        play(&mut game, ReplayEvent::GravityFloor(50));
+        play(&mut game, ReplayEvent::SoftDropRate(20));
+        let replay: Replay = serde_json::from_str(&game.state.export_replay()).unwrap();
+        let replayed = Game::from_replay(replay);
+        assert_eq!(replayed.state.gravity_floor_ms, 50);
+        assert_eq!(replayed.state.soft_drop_ms, 20);
+        // a restart starts the next game's replay with them
+        game.state.initialize_game();
+        assert_eq!(game.state.replay.settings().gravity_floor_ms, 50);
+        assert_eq!(game.state.replay.settings().soft_drop_ms, 20);
+    }
+
+    #[test]
+    fn held_soft_drop_falls_a_row_per_interval() {
+        let mut game = Game::new(Settings {
+            seed: Some(5),
+            soft_drop_ms: 50,
+            ..Settings::default()
+        });
+        assert_eq!(game.state.soft_drop_interval(), 50);
+        // held for 400 ms: a row on the press, then one every 50 ms
+        let rows = 1 + 400 / game.state.soft_drop_interval();
+        let start_y = game.state.player.position.y;
+        for _ in 0..rows {
+            assert_eq!(game.play(ReplayEvent::Step(Controls::SoftDrop)), StepOutcome::Moved);
+        }
+        assert_eq!(game.state.player.position.y - start_y, 9);
+        assert_eq!(game.state.game_status.score, 9);
+
+        // 0 follows gravity, SOFT_DROP_FACTOR times as fast
+        game.state.soft_drop_ms = 0;
+        let gravity = game.state.gravity();
+        assert_eq!(game.state.soft_drop_interval(), (gravity / SOFT_DROP_FACTOR) as u64);
+    }
+
+    #[test]
+    fn safety_rows_sit_above_the_spawn_and_take_the_stack_past_the_top() {
+        let stack = ["XXXXXXXXX."; STAGE_ROWS - 1];
+        let settings = |safety_rows| Settings {
+            seed: Some(3),
+            safety_rows,
+            script: vec![PieceType::O],
+            loop_script: true,
+            ..Settings::default()
+        };
+        let state = State::with_board(settings(BEGINNER_SAFETY_ROWS), &stack).unwrap();
+        assert_eq!(state.stage.n_rows, STAGE_ROWS + BUFFER_ROWS + BEGINNER_SAFETY_ROWS);
+        assert_eq!(state.visible_rows(), STAGE_ROWS);
+        // in the buffer row right above the visible stage, as without them
+        let buffer_row = state.stage.n_rows - STAGE_ROWS - BUFFER_ROWS;
+        assert_eq!(state.player.position.y, buffer_row as isize);
+
+        // the O can't fall below the buffer row, the next one spawns higher up
+        let mut game = Game { state };
+        assert_eq!(game.step(Controls::Bottom), StepOutcome::Locked { rows_cleared: 0 });
+        assert_eq!(game.state.player.position.y, buffer_row as isize - 2);
+        assert!(!game.is_over());
+
+        // without them the same stack tops out
+        let state = State::with_board(settings(0), &stack).unwrap();
+        let mut game = Game { state };
+        assert_eq!(game.state.player.position.y, 0);
+        assert_eq!(game.step(Controls::Bottom), StepOutcome::GameOver);
     }
 }
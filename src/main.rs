@@ -1,15 +1,22 @@
 mod utils;
 
-use log::info;
+use log::{info, Level};
 use stdweb::web::{document, IParentNode};
-use tetris::{Model, Msg};
+use tetris::Model;
 use yew::App;
 
+// the game logs on hot paths (every tick, every cell checked), so only warnings
+// and errors reach the console unless built with the `verbose` feature
+#[cfg(feature = "verbose")]
+const LOG_LEVEL: Level = Level::Info;
+#[cfg(not(feature = "verbose"))]
+const LOG_LEVEL: Level = Level::Warn;
+
 fn main() {
     yew::initialize();
     utils::set_panic_hook();
-    web_logger::init();
-    let app = App::<tetris::Model>::new();
+    web_logger::custom_init(web_logger::Config { level: LOG_LEVEL });
+    let app = App::<Model>::new();
     let element = document().query_selector(".tetris-app").unwrap().unwrap();
     app.mount(element);
     info!("starting up");